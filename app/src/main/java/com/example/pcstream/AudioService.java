@@ -8,8 +8,12 @@ import android.content.Context;
 import android.content.Intent;
 import android.media.AudioAttributes;
 import android.media.AudioFormat;
+import android.media.AudioRecord;
+import android.media.AudioTimestamp;
 import android.media.AudioTrack;
+import android.media.MediaRecorder;
 import android.media.MediaMetadata;
+import android.media.VolumeProvider;
 import android.media.session.MediaSession;
 import android.media.session.PlaybackState;
 import android.os.Binder;
@@ -37,10 +41,16 @@ public class AudioService extends Service {
     private Notification.Builder notification_builder;
     private NotificationManager notification_manager;
     private Thread runner;
-    public boolean aptx = true;
+    private Thread mic_runner;
+    private volatile boolean mic_running = false;
 
     public native int init_decode_rust();
-    public native void decode_rust(byte[] input, byte[] output);
+    public native int decode_rust(byte[] input, int length, byte[] output);
+    public native int codec_rust();
+    public native int report_rust(int buffer_ms, int playback_ms, byte[] output);
+    public native int volume_rust();
+    public native int init_encode_rust(byte[] output);
+    public native int encode_rust(byte[] input, int length, byte[] output);
     static {
         System.loadLibrary("aptx_rust");
     }
@@ -55,6 +65,96 @@ public class AudioService extends Service {
         return serveip;
     }
 
+    public void send_command(String command) {
+        Thread cmd_thread = new Thread(() -> {
+            socket_cmds = null;
+            try {
+                String cmd = command + "\0";
+                socket_cmds = new DatagramSocket();
+                DatagramPacket sendPacket = new DatagramPacket(cmd.getBytes(StandardCharsets.UTF_8),
+                        cmd.length(), InetAddress.getByName(prev_ip.substring(1)), 4053);
+                socket_cmds.send(sendPacket);
+            } catch (IOException e) {
+                e.printStackTrace();
+            }
+        });
+        cmd_thread.start();
+    }
+
+    // Codec ids match protocol::Codec on the server, 0 is PCM
+    public void toggle_aptx() {
+        send_command(codec_rust() == 0 ? "CODEC APTX" : "CODEC PCM");
+    }
+
+    public boolean mic_enabled() {
+        return mic_running;
+    }
+
+    // Records the microphone and sends it to the server, which plays it into a virtual source.
+    // Needs the RECORD_AUDIO permission, granted by MainActivity before calling this.
+    public void toggle_mic() {
+        if (mic_running) {
+            mic_running = false;
+            try {
+                mic_runner.join();
+            } catch (InterruptedException e) {
+                e.printStackTrace();
+            }
+            Log.d("PCstream", "Microphone stopped");
+            return;
+        }
+        if (prev_ip.isEmpty()) {
+            Log.d("PCstream", "No server to send the microphone to");
+            return;
+        }
+        mic_running = true;
+        mic_runner = new Thread(() -> {
+            // 512 frames of 16 bit mono, the packet size of the stream from the server
+            int chunk = 1024;
+            AudioRecord recorder;
+            try {
+                recorder = new AudioRecord.Builder()
+                        .setAudioSource(MediaRecorder.AudioSource.VOICE_COMMUNICATION)
+                        .setAudioFormat(new AudioFormat.Builder()
+                                .setEncoding(AudioFormat.ENCODING_PCM_16BIT)
+                                .setSampleRate(48000)
+                                .setChannelMask(AudioFormat.CHANNEL_IN_MONO)
+                                .build())
+                        .setBufferSizeInBytes(Math.max(4 * chunk, AudioRecord.getMinBufferSize(48000,
+                                AudioFormat.CHANNEL_IN_MONO, AudioFormat.ENCODING_PCM_16BIT)))
+                        .build();
+            } catch (SecurityException e) {
+                Log.e("PCstream", "No permission to record: " + e.getMessage());
+                mic_running = false;
+                return;
+            }
+            byte[] samples = new byte[chunk];
+            byte[] encoded = new byte[chunk * 2];
+            try (DatagramSocket socket = new DatagramSocket()) {
+                InetAddress server = InetAddress.getByName(prev_ip.substring(1));
+                int marker = init_encode_rust(encoded);
+                socket.send(new DatagramPacket(encoded, marker, server, 4054));
+                recorder.startRecording();
+                while (mic_running) {
+                    int read = recorder.read(samples, 0, chunk);
+                    if (read <= 0)
+                        continue;
+                    int length = encode_rust(samples, read, encoded);
+                    if (length > 0)
+                        socket.send(new DatagramPacket(encoded, length, server, 4054));
+                }
+            } catch (IOException e) {
+                e.printStackTrace();
+            } finally {
+                recorder.stop();
+                recorder.release();
+                mic_running = false;
+            }
+        });
+        mic_runner.start();
+        Log.d("PCstream", "Microphone started");
+    }
+
     public DatagramSocket create_socket(Integer port) throws Exception {
         DatagramSocket udpSocket = null;
         int MAX_TRIES = 10, cnt = 0;
@@ -95,6 +195,8 @@ public class AudioService extends Service {
             }
             Log.d("PCstream", "Trying to init aptx ctx");
         }
+        // Fixed whatever the server's --profile, the receive buffers below take its largest
+        // packet (buffering::MAX_PACKET_FRAMES) and the low latency track sizes itself up
         int chunk = 2048;
         AudioTrack player = new AudioTrack.Builder()
                 .setAudioAttributes(new AudioAttributes.Builder()
@@ -119,6 +221,22 @@ public class AudioService extends Service {
         MediaSession media_session = new MediaSession(getApplicationContext(), getPackageName());
         PlaybackState.Builder state_builder = new PlaybackState.Builder();
 
+        // Volume keys control the volume of the stream on the PC, kept in sync with it below
+        VolumeProvider volume_provider = new VolumeProvider(VolumeProvider.VOLUME_CONTROL_ABSOLUTE, 100, 100) {
+            @Override
+            public void onSetVolumeTo(int volume) {
+                send_command("VOLUME " + volume);
+            }
+
+            @Override
+            public void onAdjustVolume(int direction) {
+                if (direction > 0)
+                    send_command("VOLUME UP");
+                else if (direction < 0)
+                    send_command("VOLUME DOWN");
+            }
+        };
+        media_session.setPlaybackToRemote(volume_provider);
         media_session.setActive(true);
         state_builder.setActions( PlaybackState.ACTION_PLAY
                 | PlaybackState.ACTION_PLAY_PAUSE | PlaybackState.ACTION_PAUSE
@@ -145,37 +263,13 @@ public class AudioService extends Service {
 
             @Override
             public void onSkipToPrevious() {
-                Thread cmd_thread = new Thread(() -> {
-                    socket_cmds = null;
-                    try {
-                        String cmd = "PREV\0";
-                        socket_cmds = new DatagramSocket();
-                        DatagramPacket sendPacket = new DatagramPacket(cmd.getBytes(StandardCharsets.UTF_8),
-                                cmd.length(), InetAddress.getByName(prev_ip.substring(1)), 4053);
-                        socket_cmds.send(sendPacket);
-                    } catch (IOException e) {
-                        e.printStackTrace();
-                    }
-                });
-                cmd_thread.start();
+                send_command("PREV");
                 Log.d("PCstream", "PREV");
             }
 
             @Override
             public void onSkipToNext() {
-                Thread cmd_thread = new Thread(() -> {
-                    socket_cmds = null;
-                    try {
-                        String cmd = "NEXT\0";
-                        socket_cmds = new DatagramSocket();
-                        DatagramPacket sendPacket = new DatagramPacket(cmd.getBytes(StandardCharsets.UTF_8),
-                                cmd.length(), InetAddress.getByName(prev_ip.substring(1)), 4053);
-                        socket_cmds.send(sendPacket);
-                    } catch (IOException e) {
-                        e.printStackTrace();
-                    }
-                });
-                cmd_thread.start();
+                send_command("NEXT");
                 Log.d("PCstream", "NEXT");
             }
         });
@@ -204,20 +298,17 @@ public class AudioService extends Service {
             e.printStackTrace();
         }
         runner = new Thread(() -> {
-            byte[] message_aptx = new byte[chunk/4];
-            DatagramPacket packet_aptx = new DatagramPacket(message_aptx, chunk/4);
-            byte[] message = new byte[chunk];
-            DatagramPacket packet_no_aptx = new DatagramPacket(message, chunk);
-            DatagramPacket packet;
+            // Large enough for a PCM packet, the codec is announced by the server in band
+            byte[] message_in = new byte[2 * chunk];
+            DatagramPacket packet = new DatagramPacket(message_in, message_in.length);
+            byte[] message = new byte[2 * chunk];
+            byte[] report = new byte[64];
+            AudioTimestamp timestamp = new AudioTimestamp();
+            long frames_written = 0;
             int pkg_count = 0;
             while (running) {
                 try {
-                    if(aptx) {
-                        packet = packet_aptx;
-                    }
-                    else {
-                        packet = packet_no_aptx;
-                    }
+                    packet.setLength(message_in.length);
                     socket_stream.receive(packet);
                     if(packet.getAddress().toString() != prev_ip){
                         if(serverip_observer != null)
@@ -226,10 +317,30 @@ public class AudioService extends Service {
                         notification_builder.setContentText(prev_ip);
                         notification_manager.notify(0, notification_builder.build());
                     }
-                    if(aptx) {
-                        decode_rust(message_aptx, message);
+                    int decoded = decode_rust(message_in, packet.getLength(), message);
+                    int volume = volume_rust();
+                    if(volume >= 0 && volume != volume_provider.getCurrentVolume())
+                        volume_provider.setCurrentVolume(volume);
+                    if(decoded > 0) {
+                        player.write(message, 0, decoded);
+                        frames_written += decoded / 4;
+                    }
+                    // Feedback for the server to pick the codec, reports are due once a second
+                    long head = player.getPlaybackHeadPosition() & 0xffffffffL;
+                    long buffered = frames_written - head;
+                    // Frames past the head but not out of the speaker yet
+                    long playback = 0;
+                    if(player.getTimestamp(timestamp)) {
+                        long presented = timestamp.framePosition
+                                + (System.nanoTime() - timestamp.nanoTime) * 48000 / 1000000000L;
+                        playback = Math.max(0, head - presented);
+                    }
+                    int report_len = report_rust((int) (buffered * 1000 / 48000),
+                            (int) (playback * 1000 / 48000), report);
+                    if(report_len > 0) {
+                        socket_stream.send(new DatagramPacket(report, report_len,
+                                packet.getAddress(), 4052));
                     }
-                    player.write(message, 0, chunk);
                     pkg_count += 1;
                 } catch (Exception e) {
                     Log.d("PCstream", "Something bad happen");
@@ -251,6 +362,8 @@ public class AudioService extends Service {
     @Override
     public void onDestroy()
     {
+        if (mic_running)
+            toggle_mic();
         running = false;
         try {
             runner.join();
@@ -4,6 +4,7 @@ import android.content.ComponentName;
 import android.content.Context;
 import android.content.Intent;
 import android.content.ServiceConnection;
+import android.content.pm.PackageManager;
 import android.net.DhcpInfo;
 import android.net.wifi.WifiManager;
 import android.os.Bundle;
@@ -64,6 +65,8 @@ class BroadcastAddress implements Runnable {
 
 public class MainActivity extends AppCompatActivity {
 
+    private static final int MIC_PERMISSION_REQUEST = 1;
+
     private Intent audio_intent;
     private AudioService audio_service;
     private ServiceConnection audio_conn;
@@ -78,6 +81,7 @@ public class MainActivity extends AppCompatActivity {
 
         serverip_textview = findViewById(R.id.server_ip);
         Button aptx_button = findViewById(R.id.button_aptx);
+        Button mic_button = findViewById(R.id.button_mic);
         Button stop_button = findViewById(R.id.button_stop);
         Button start_button = findViewById(R.id.button_start);
 
@@ -102,8 +106,17 @@ public class MainActivity extends AppCompatActivity {
         bindService(audio_intent, audio_conn, Context.BIND_AUTO_CREATE);
 
         aptx_button.setOnClickListener(v -> {
-            // TODO change buffer of message size
-            audio_service.aptx = !audio_service.aptx;
+            audio_service.toggle_aptx();
+        });
+        mic_button.setOnClickListener(v -> {
+            if (checkSelfPermission(android.Manifest.permission.RECORD_AUDIO)
+                    != PackageManager.PERMISSION_GRANTED) {
+                requestPermissions(new String[]{android.Manifest.permission.RECORD_AUDIO},
+                        MIC_PERMISSION_REQUEST);
+                return;
+            }
+            if (audio_conn_bound)
+                audio_service.toggle_mic();
         });
         stop_button.setOnClickListener(v -> {
             if(audio_conn_bound){
@@ -132,6 +145,14 @@ public class MainActivity extends AppCompatActivity {
         });
     }
 
+    @Override
+    public void onRequestPermissionsResult(int requestCode, String[] permissions, int[] grantResults) {
+        super.onRequestPermissionsResult(requestCode, permissions, grantResults);
+        if (requestCode == MIC_PERMISSION_REQUEST && grantResults.length > 0
+                && grantResults[0] == PackageManager.PERMISSION_GRANTED && audio_conn_bound)
+            audio_service.toggle_mic();
+    }
+
     @Override
     public void onResume() {
         super.onResume();
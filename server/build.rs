@@ -0,0 +1,24 @@
+fn main() {
+    #[cfg(feature = "capi")]
+    generate_header();
+}
+
+/// Writes the header for src/capi.rs to OUT_DIR, where a test compares it with the committed
+/// include/openaptx.h. Only the C API and the context it hands out are parsed, the JNI exports
+/// of the Android receiver stay out of the header.
+#[cfg(feature = "capi")]
+fn generate_header() {
+    for file in ["cbindgen.toml", "src/capi.rs", "src/aptx.rs"] {
+        println!("cargo:rerun-if-changed={file}");
+    }
+    let config = cbindgen::Config::from_file("cbindgen.toml").expect("Fail to read cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/capi.rs")
+        .with_src("src/aptx.rs")
+        .generate()
+        .expect("Fail to generate the C header")
+        .write_to_file(
+            std::path::Path::new(&std::env::var_os("OUT_DIR").unwrap()).join("openaptx.h"),
+        );
+}
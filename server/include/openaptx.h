@@ -0,0 +1,122 @@
+/*
+ * Open Source implementation of Audio Processing Technology codec (aptX)
+ * Rust version, C API compatible with libopenaptx.
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ */
+
+#ifndef OPENAPTX_H
+#define OPENAPTX_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit */
+
+#include <stddef.h>
+
+/**
+ * State of an aptX or aptX HD stream, usable either as an encoder or as a decoder.
+ *
+ * Audio is stereo, 16 bit (aptX) or 24 bit (aptX HD) signed little endian, interleaved. Every
+ * group of 4 frames is coded as one codeword of 4 bytes (aptX) or 6 bytes (aptX HD).
+ */
+struct aptx_context;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Version of the libopenaptx API implemented.
+ */
+extern const int aptx_major;
+
+extern const int aptx_minor;
+
+extern const int aptx_patch;
+
+/**
+ * Allocates a context for aptX (`hd` = 0) or aptX HD (`hd` != 0), to be freed with
+ * `aptx_finish`.
+ */
+struct aptx_context *aptx_init(int hd);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init` and not be freed yet.
+ */
+void aptx_reset(struct aptx_context *ctx);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init` or be NULL, it is invalid afterwards.
+ */
+void aptx_finish(struct aptx_context *ctx);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init`, `input` and `output` must be valid for `input_size` and
+ * `output_size` bytes, `written` must be valid or NULL.
+ */
+size_t aptx_encode(struct aptx_context *ctx,
+                   const unsigned char *input,
+                   size_t input_size,
+                   unsigned char *output,
+                   size_t output_size,
+                   size_t *written);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init`, `output` must be valid for `output_size` bytes, `written`
+ * must be valid or NULL.
+ */
+int aptx_encode_finish(struct aptx_context *ctx,
+                       unsigned char *output,
+                       size_t output_size,
+                       size_t *written);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init`, `input` and `output` must be valid for `input_size` and
+ * `output_size` bytes, `written` must be valid or NULL.
+ */
+size_t aptx_decode(struct aptx_context *ctx,
+                   const unsigned char *input,
+                   size_t input_size,
+                   unsigned char *output,
+                   size_t output_size,
+                   size_t *written);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init`, `input` and `output` must be valid for `input_size` and
+ * `output_size` bytes, `written`, `synced` and `dropped` must be valid or NULL.
+ */
+size_t aptx_decode_sync(struct aptx_context *ctx,
+                        const unsigned char *input,
+                        size_t input_size,
+                        unsigned char *output,
+                        size_t output_size,
+                        size_t *written,
+                        int *synced,
+                        size_t *dropped);
+
+/**
+ * # Safety
+ *
+ * `ctx` must come from `aptx_init`.
+ */
+size_t aptx_decode_sync_finish(struct aptx_context *ctx);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* OPENAPTX_H */
@@ -0,0 +1,208 @@
+//! Codec adaptation from receiver feedback. Receivers measure what reaches them with a
+//! [`Monitor`] and send the [`Report`]s back to the server, which steps each of them along
+//! [`LADDER`] with an [`Adaptation`].
+
+use crate::protocol::{Codec, Report, SAMPLE_RATE};
+use log::info;
+use std::time::{Duration, Instant};
+
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Codecs from the highest bitrate down, adaptation moves one step at a time.
+pub const LADDER: [Codec; 4] = [Codec::Pcm, Codec::AptxHd, Codec::Aptx, Codec::AptxLow];
+
+/// Fraction of lost packets above which a report is bad, and below which it is good.
+const DEGRADE_LOSS: f64 = 0.02;
+const UPGRADE_LOSS: f64 = 0.002;
+const DEGRADE_JITTER_US: u32 = 8000;
+const UPGRADE_JITTER_US: u32 = 3000;
+/// A receiver buffering less than this is about to underrun.
+const MIN_BUFFER_MS: u32 = 5;
+/// Reports in a row needed to step down, and to step back up. Stepping up is much slower so
+/// the stream does not flap between two codecs.
+const DEGRADE_REPORTS: usize = 2;
+const UPGRADE_REPORTS: usize = 10;
+
+/// Receiver side, accounts for the audio packets arriving between two reports.
+#[derive(Default)]
+pub struct Monitor {
+    start: Option<Instant>,
+    last: Option<Instant>,
+    frames: usize,
+    packets: u32,
+    packet_frames: usize,
+    jitter_us: f64,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    /// Records an audio packet carrying `frames` frames.
+    pub fn packet(&mut self, now: Instant, frames: usize) {
+        let Some(last) = self.last.replace(now) else {
+            self.start = Some(now);
+            return;
+        };
+        // Interarrival jitter as in RFC 3550, against the duration of the previous packet
+        if self.packet_frames > 0 {
+            let expected = self.packet_frames as f64 * 1e6 / SAMPLE_RATE as f64;
+            let deviation = (now.duration_since(last).as_micros() as f64 - expected).abs();
+            self.jitter_us += (deviation - self.jitter_us) / 16.0;
+        }
+        self.packet_frames = frames;
+        self.frames += frames;
+        self.packets += 1;
+    }
+
+    /// Silence is not loss, starts the next report over once audio comes back.
+    pub fn silence(&mut self) {
+        self.start = None;
+        self.last = None;
+        self.frames = 0;
+        self.packets = 0;
+    }
+
+    /// Report for the audio received since the previous one, once [`REPORT_INTERVAL`] is over.
+    /// `buffer_ms` is the audio queued for playback, `playback_ms` the delay of the output after
+    /// it, 0 when unknown.
+    pub fn report(&mut self, now: Instant, buffer_ms: u32, playback_ms: u32) -> Option<Report> {
+        let (start, last) = (self.start?, self.last?);
+        if now.duration_since(start) < REPORT_INTERVAL || self.packets == 0 {
+            return None;
+        }
+        let expected = last.duration_since(start).as_secs_f64() * SAMPLE_RATE as f64;
+        let lost = ((expected - self.frames as f64) / self.packet_frames as f64).round();
+        let report = Report {
+            received: self.packets,
+            lost: lost.max(0.0) as u32,
+            jitter_us: self.jitter_us as u32,
+            buffer_ms,
+            playback_ms,
+        };
+        self.start = Some(last);
+        self.frames = 0;
+        self.packets = 0;
+        Some(report)
+    }
+}
+
+/// Server side, one per receiver.
+#[derive(Default)]
+pub struct Adaptation {
+    codec: Option<Codec>,
+    bad: usize,
+    good: usize,
+}
+
+impl Adaptation {
+    pub fn new() -> Adaptation {
+        Adaptation::default()
+    }
+
+    /// Feeds a report about a stream currently using `current`, returns the codec to switch to.
+    pub fn update(&mut self, current: Codec, report: &Report) -> Option<Codec> {
+        if self.codec != Some(current) {
+            // First report, or the codec was changed by hand
+            *self = Adaptation {
+                codec: Some(current),
+                ..Adaptation::default()
+            };
+        }
+        let total = (report.received + report.lost).max(1) as f64;
+        let loss = report.lost as f64 / total;
+        let bad = loss > DEGRADE_LOSS
+            || report.jitter_us > DEGRADE_JITTER_US
+            || report.buffer_ms < MIN_BUFFER_MS;
+        let good = loss < UPGRADE_LOSS && report.jitter_us < UPGRADE_JITTER_US && !bad;
+        (self.bad, self.good) = match (bad, good) {
+            (true, _) => (self.bad + 1, 0),
+            (_, true) => (0, self.good + 1),
+            _ => (0, 0),
+        };
+
+        let step = LADDER.iter().position(|&codec| codec == current)?;
+        let next = if self.bad >= DEGRADE_REPORTS {
+            LADDER.get(step + 1)
+        } else if self.good >= UPGRADE_REPORTS {
+            step.checked_sub(1).map(|step| &LADDER[step])
+        } else {
+            None
+        }
+        .copied()?;
+        info!(
+            "Switching codec {current} -> {next}: {:.1}% lost, {} us jitter, {} ms buffered",
+            loss * 100.0,
+            report.jitter_us,
+            report.buffer_ms
+        );
+        *self = Adaptation {
+            codec: Some(next),
+            ..Adaptation::default()
+        };
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLEAN: Report = Report {
+        received: 94,
+        lost: 0,
+        jitter_us: 500,
+        buffer_ms: 20,
+        playback_ms: 0,
+    };
+    const LOSSY: Report = Report {
+        received: 80,
+        lost: 14,
+        jitter_us: 500,
+        buffer_ms: 20,
+        playback_ms: 0,
+    };
+
+    #[test]
+    fn test_adaptation_hysteresis() {
+        let mut adaptation = Adaptation::new();
+        assert_eq!(adaptation.update(Codec::Pcm, &LOSSY), None);
+        assert_eq!(adaptation.update(Codec::Pcm, &LOSSY), Some(Codec::AptxHd));
+        assert_eq!(adaptation.update(Codec::AptxHd, &LOSSY), None);
+        assert_eq!(adaptation.update(Codec::AptxHd, &LOSSY), Some(Codec::Aptx));
+        assert_eq!(adaptation.update(Codec::Aptx, &LOSSY), None);
+        assert_eq!(adaptation.update(Codec::Aptx, &LOSSY), Some(Codec::AptxLow));
+        assert_eq!(adaptation.update(Codec::AptxLow, &LOSSY), None);
+        assert_eq!(adaptation.update(Codec::AptxLow, &LOSSY), None);
+
+        // A single lossy report restarts the count
+        for _ in 0..UPGRADE_REPORTS - 1 {
+            assert_eq!(adaptation.update(Codec::Aptx, &CLEAN), None);
+        }
+        assert_eq!(adaptation.update(Codec::Aptx, &LOSSY), None);
+        for _ in 0..UPGRADE_REPORTS - 1 {
+            assert_eq!(adaptation.update(Codec::Aptx, &CLEAN), None);
+        }
+        assert_eq!(adaptation.update(Codec::Aptx, &CLEAN), Some(Codec::AptxHd));
+    }
+
+    #[test]
+    fn test_monitor_loss() {
+        let mut monitor = Monitor::new();
+        let start = Instant::now();
+        let packet = Duration::from_micros(512 * 1_000_000 / SAMPLE_RATE as u64);
+        for n in 0..120u32 {
+            // Every tenth packet is lost
+            if n % 10 != 5 {
+                monitor.packet(start + packet * n, 512);
+            }
+        }
+        assert_eq!(monitor.report(start + packet * 50, 20, 5), None);
+        let report = monitor.report(start + packet * 120, 20, 5).unwrap();
+        assert_eq!(report.received + report.lost, 119);
+        assert_eq!(report.lost, 12);
+        assert!(report.jitter_us > 0);
+        assert_eq!((report.buffer_ms, report.playback_ms), (20, 5));
+    }
+}
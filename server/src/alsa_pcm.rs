@@ -0,0 +1,180 @@
+//! ALSA capture and playback, for machines without a sound server. Periods are one packet long
+//! so the card wakes us up once per packet, and the buffer holds `periods` of them.
+//!
+//! Use `plughw:` devices when the card does not take 24 bit samples. To stream what a headless
+//! box plays, load `snd-aloop`, play to `hw:Loopback,0` and capture `hw:Loopback,1`.
+
+use alsa::{
+    pcm::{Access, Format, Frames, HwParams, PCM},
+    Direction, ValueOr,
+};
+use log::{info, warn};
+use std::{io, time::Duration};
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: usize = 2;
+
+fn alsa_error(device: &str, err: alsa::Error) -> io::Error {
+    io::Error::other(format!("ALSA {device}: {err}"))
+}
+
+fn open(
+    device: &str,
+    direction: Direction,
+    bytes_per_sample: usize,
+    frames_per_packet: usize,
+    periods: usize,
+) -> io::Result<PCM> {
+    let pcm = PCM::new(device, direction, false).map_err(|err| alsa_error(device, err))?;
+    {
+        let err = |err| alsa_error(device, err);
+        let params = HwParams::any(&pcm).map_err(err)?;
+        params.set_access(Access::RWInterleaved).map_err(err)?;
+        params.set_channels(CHANNELS as u32).map_err(err)?;
+        params
+            .set_rate(SAMPLE_RATE, ValueOr::Nearest)
+            .map_err(err)?;
+        // The nearest rate would play at the wrong speed
+        let rate = params.get_rate().map_err(err)?;
+        if rate != SAMPLE_RATE {
+            return Err(io::Error::other(format!(
+                "ALSA {device} runs at {rate} Hz, the stream needs {SAMPLE_RATE} Hz, try the \
+                 plughw: device"
+            )));
+        }
+        let format = if bytes_per_sample == 3 {
+            Format::S243LE
+        } else {
+            Format::S16LE
+        };
+        params.set_format(format).map_err(|_| {
+            io::Error::other(format!(
+                "ALSA {device}: {format} not supported, try the plughw: device"
+            ))
+        })?;
+        let period = params
+            .set_period_size_near(frames_per_packet as Frames, ValueOr::Nearest)
+            .map_err(err)?;
+        let buffer = params
+            .set_buffer_size_near(period * periods.max(2) as Frames)
+            .map_err(err)?;
+        pcm.hw_params(&params).map_err(err)?;
+        info!("ALSA {device}: {format}, period {period} frames, buffer {buffer} frames");
+
+        if direction == Direction::Playback {
+            // Start on the first packet, the buffer fills up with the network jitter
+            let sw_params = pcm.sw_params_current().map_err(err)?;
+            sw_params.set_start_threshold(period).map_err(err)?;
+            pcm.sw_params(&sw_params).map_err(err)?;
+        }
+    }
+    Ok(pcm)
+}
+
+/// Frames in the card buffer, captured and not read, or written and not played.
+fn delay(pcm: &PCM) -> Duration {
+    let frames = pcm.delay().unwrap_or(0).max(0);
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
+pub struct AlsaCapture {
+    device: String,
+    pcm: PCM,
+    bytes_per_frame: usize,
+}
+
+impl AlsaCapture {
+    pub fn new(
+        device: &str,
+        bytes_per_sample: usize,
+        frames_per_packet: usize,
+        periods: usize,
+    ) -> io::Result<AlsaCapture> {
+        let pcm = open(
+            device,
+            Direction::Capture,
+            bytes_per_sample,
+            frames_per_packet,
+            periods,
+        )?;
+        Ok(AlsaCapture {
+            device: device.to_string(),
+            pcm,
+            bytes_per_frame: CHANNELS * bytes_per_sample,
+        })
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.pcm.io_bytes().readi(&mut buffer[filled..]) {
+                Ok(frames) => filled += frames * self.bytes_per_frame,
+                Err(err) => {
+                    warn!("ALSA {}: {err}, recovering", self.device);
+                    self.pcm
+                        .try_recover(err, true)
+                        .map_err(|err| alsa_error(&self.device, err))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn latency(&self) -> Duration {
+        delay(&self.pcm)
+    }
+}
+
+pub struct AlsaPlayback {
+    device: String,
+    pcm: PCM,
+    bytes_per_frame: usize,
+}
+
+impl AlsaPlayback {
+    pub fn new(
+        device: &str,
+        bytes_per_sample: usize,
+        frames_per_packet: usize,
+        periods: usize,
+    ) -> io::Result<AlsaPlayback> {
+        let pcm = open(
+            device,
+            Direction::Playback,
+            bytes_per_sample,
+            frames_per_packet,
+            periods,
+        )?;
+        Ok(AlsaPlayback {
+            device: device.to_string(),
+            pcm,
+            bytes_per_frame: CHANNELS * bytes_per_sample,
+        })
+    }
+
+    pub fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < pcm.len() {
+            match self.pcm.io_bytes().writei(&pcm[written..]) {
+                Ok(frames) => written += frames * self.bytes_per_frame,
+                Err(err) => {
+                    warn!("ALSA {}: {err}, recovering", self.device);
+                    self.pcm
+                        .try_recover(err, true)
+                        .map_err(|err| alsa_error(&self.device, err))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn drain(&mut self) -> io::Result<()> {
+        self.pcm
+            .drain()
+            .map_err(|err| alsa_error(&self.device, err))
+    }
+
+    pub fn latency(&self) -> Duration {
+        delay(&self.pcm)
+    }
+}
@@ -0,0 +1,285 @@
+use aptx_rust::aptx::AptxContext;
+use std::{f64::consts::PI, fmt::Display};
+
+const CHANNELS: usize = 2;
+const FULL_SCALE: f64 = (1 << 23) as f64;
+
+pub const SUBBAND_NAMES: [&str; 4] = ["LF", "MLF", "MHF", "HF"];
+
+#[derive(Debug, Clone, Copy)]
+pub enum TestSignal {
+    Sine(f64),
+    MultiTone,
+    Noise,
+    Sweep,
+}
+
+impl TestSignal {
+    /// Generates `frames` of interleaved stereo samples scaled to 24 bits at -3 dBFS.
+    pub fn generate(&self, frames: usize, rate: u32) -> Vec<i32> {
+        let amplitude = FULL_SCALE * 0.7;
+        let rate = rate as f64;
+        let mut seed = 0x1234_5678_u32;
+        let mut samples = Vec::with_capacity(frames * CHANNELS);
+        for n in 0..frames {
+            let t = n as f64 / rate;
+            let value = match self {
+                TestSignal::Sine(freq) => (2.0 * PI * freq * t).sin(),
+                TestSignal::MultiTone => {
+                    [100.0, 1000.0, 5000.0, 12000.0]
+                        .iter()
+                        .map(|freq| (2.0 * PI * freq * t).sin())
+                        .sum::<f64>()
+                        / 4.0
+                }
+                TestSignal::Noise => {
+                    seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (seed as i32) as f64 / i32::MAX as f64
+                }
+                TestSignal::Sweep => {
+                    let duration = frames as f64 / rate;
+                    let (f0, f1) = (20.0_f64, 20000.0_f64);
+                    let k = (f1 / f0).ln();
+                    let phase = 2.0 * PI * f0 * duration / k * ((t / duration * k).exp() - 1.0);
+                    phase.sin()
+                }
+            };
+            let sample = (value * amplitude) as i32;
+            samples.extend_from_slice(&[sample; CHANNELS]);
+        }
+        samples
+    }
+
+    pub fn fundamental(&self) -> Option<f64> {
+        match self {
+            TestSignal::Sine(freq) => Some(*freq),
+            _ => None,
+        }
+    }
+}
+
+impl Display for TestSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestSignal::Sine(freq) => write!(f, "sine {freq} Hz"),
+            TestSignal::MultiTone => write!(f, "multitone"),
+            TestSignal::Noise => write!(f, "white noise"),
+            TestSignal::Sweep => write!(f, "log sweep"),
+        }
+    }
+}
+
+pub struct Report {
+    pub hd: bool,
+    pub frames: usize,
+    pub delay: usize,
+    pub snr_db: f64,
+    pub peak_error: i32,
+    pub subband_error: [f64; 4],
+    pub thd_n_db: Option<f64>,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} over {} frames (decoder delay {} samples)",
+            if self.hd { "aptX HD" } else { "aptX" },
+            self.frames,
+            self.delay
+        )?;
+        writeln!(f, "  SNR:        {:8.2} dB", self.snr_db)?;
+        writeln!(
+            f,
+            "  Peak error: {:8.2} dBFS ({} LSB @ 24 bit)",
+            20.0 * (self.peak_error.max(1) as f64 / FULL_SCALE).log10(),
+            self.peak_error
+        )?;
+        match self.thd_n_db {
+            Some(thd_n) => writeln!(f, "  THD+N:      {thd_n:8.2} dB")?,
+            None => writeln!(f, "  THD+N:           n/a (needs a sine input)")?,
+        }
+        write!(f, "  Subband quantization error (mean):")?;
+        for (name, error) in SUBBAND_NAMES.iter().zip(self.subband_error) {
+            write!(f, " {name} {error:.1}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reduces 24 bit samples to what the codec actually sees: 16 bits for aptX, 24 for aptX HD.
+fn codec_input(samples: &[i32], hd: bool) -> (Vec<i32>, Vec<u8>) {
+    let mut reference = Vec::with_capacity(samples.len());
+    let mut pcm = Vec::with_capacity(samples.len() * 3);
+    for &sample in samples {
+        let sample = sample.clamp(-(1 << 23), (1 << 23) - 1);
+        if hd {
+            pcm.extend_from_slice(&sample.to_le_bytes()[..3]);
+            reference.push(sample);
+        } else {
+            pcm.extend_from_slice(&((sample >> 8) as i16).to_le_bytes());
+            reference.push(sample >> 8 << 8);
+        }
+    }
+    (reference, pcm)
+}
+
+fn pcm_to_i24(pcm: &[u8], hd: bool) -> Vec<i32> {
+    if hd {
+        pcm.chunks_exact(3)
+            .map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8)
+            .collect()
+    } else {
+        pcm.chunks_exact(2)
+            .map(|s| (i16::from_le_bytes([s[0], s[1]]) as i32) << 8)
+            .collect()
+    }
+}
+
+/// Runs interleaved stereo samples through an encode/decode cycle, returning the decoded
+/// samples scaled to 24 bits and the mean quantization error of every subband.
+pub fn roundtrip(samples: &[i32], hd: bool) -> (Vec<i32>, [f64; 4]) {
+    let (_, pcm) = codec_input(samples, hd);
+    let sample_size = if hd { 6 } else { 4 };
+    let block = sample_size / 2 * CHANNELS * 4;
+
+    let mut encoder = AptxContext::new(hd);
+    let mut encoded = vec![0u8; (pcm.len() / block + AptxContext::LATENCY_SAMPLES) * sample_size];
+    let mut opos = 0;
+    let mut error_sum = [0f64; 4];
+    let mut codewords = 0;
+    for chunk in pcm.chunks_exact(block) {
+        let mut written = 0;
+        encoder.encode(chunk, &mut encoded[opos..], &mut written);
+        opos += written;
+        for channel in encoder.quantize_errors() {
+            for (sum, error) in error_sum.iter_mut().zip(channel) {
+                *sum += error as f64;
+            }
+        }
+        codewords += 1;
+    }
+    let mut written = 0;
+    encoder.encode_finish(&mut encoded[opos..], &mut written);
+    opos += written;
+    encoded.truncate(opos);
+
+    let mut decoder = AptxContext::new(hd);
+    let mut decoded = vec![0u8; encoded.len() / sample_size * block];
+    decoder.decode(&encoded, &mut decoded, &mut written);
+    decoded.truncate(written);
+
+    let subband_error = error_sum.map(|sum| sum / (codewords * CHANNELS).max(1) as f64);
+    (pcm_to_i24(&decoded, hd), subband_error)
+}
+
+fn error_energy(reference: &[i32], decoded: &[i32], delay: usize) -> f64 {
+    reference
+        .iter()
+        .zip(decoded.iter().skip(delay * CHANNELS))
+        .map(|(&r, &d)| (d as f64 - r as f64).powi(2))
+        .sum()
+}
+
+/// Finds the offset of `decoded` relative to `reference`, searching around the nominal codec
+/// latency left after the decoder skipped its leading codewords.
+fn find_delay(reference: &[i32], decoded: &[i32]) -> usize {
+    (0..=AptxContext::LATENCY_SAMPLES / 4)
+        .min_by(|&a, &b| {
+            error_energy(reference, decoded, a).total_cmp(&error_energy(reference, decoded, b))
+        })
+        .unwrap_or(0)
+}
+
+/// THD+N of one channel: power left after removing the best fitting sine at `freq` (and DC),
+/// relative to the power of that sine.
+fn thd_n(signal: &[f64], freq: f64, rate: u32) -> f64 {
+    let w = 2.0 * PI * freq / rate as f64;
+    let n = signal.len() as f64;
+    let (mut s, mut c, mut mean) = (0.0, 0.0, 0.0);
+    for (i, x) in signal.iter().enumerate() {
+        s += x * (w * i as f64).sin();
+        c += x * (w * i as f64).cos();
+        mean += x;
+    }
+    let (s, c, mean) = (2.0 * s / n, 2.0 * c / n, mean / n);
+    let mut residual = 0.0;
+    for (i, x) in signal.iter().enumerate() {
+        let fit = mean + s * (w * i as f64).sin() + c * (w * i as f64).cos();
+        residual += (x - fit).powi(2);
+    }
+    let fundamental = (s * s + c * c) / 2.0 * n;
+    10.0 * (residual / fundamental).log10()
+}
+
+/// Measures the quality cost of aptX (or aptX HD) on interleaved stereo samples scaled to 24
+/// bits. `fundamental` enables the THD+N measurement for sine inputs.
+pub fn analyze(samples: &[i32], hd: bool, fundamental: Option<f64>, rate: u32) -> Report {
+    let (reference, _) = codec_input(samples, hd);
+    let (decoded, subband_error) = roundtrip(samples, hd);
+    let delay = find_delay(&reference, &decoded);
+    let aligned = &decoded[(delay * CHANNELS).min(decoded.len())..];
+    // Leave out the start-up transient of the prediction filters and the tail, where the
+    // filters see the stale samples encode_finish flushes with
+    let len = reference.len().min(aligned.len());
+    let settle = (len / CHANNELS / 4).min(rate as usize / 10) * CHANNELS;
+    let len = len
+        .saturating_sub(AptxContext::LATENCY_SAMPLES * CHANNELS)
+        .max(settle);
+    let (reference, aligned) = (&reference[settle..len], &aligned[settle..len]);
+
+    let signal: f64 = reference.iter().map(|&r| (r as f64).powi(2)).sum();
+    let noise = error_energy(reference, aligned, 0);
+    let peak_error = reference
+        .iter()
+        .zip(aligned)
+        .map(|(r, d)| (d - r).abs())
+        .max()
+        .unwrap_or(0);
+    let thd_n_db = fundamental.map(|freq| {
+        (0..CHANNELS)
+            .map(|ch| {
+                let channel: Vec<f64> = aligned
+                    .iter()
+                    .skip(ch)
+                    .step_by(CHANNELS)
+                    .map(|&x| x as f64)
+                    .collect();
+                thd_n(&channel, freq, rate)
+            })
+            .fold(f64::NEG_INFINITY, f64::max)
+    });
+
+    Report {
+        hd,
+        frames: reference.len() / CHANNELS,
+        delay,
+        snr_db: 10.0 * (signal / noise.max(1.0)).log10(),
+        peak_error,
+        subband_error,
+        thd_n_db,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_length() {
+        let samples = TestSignal::Sine(1000.0).generate(4800, 48000);
+        let (decoded, _) = roundtrip(&samples, false);
+        assert!(decoded.len() >= samples.len());
+    }
+
+    #[test]
+    fn test_analyze_sine() {
+        let signal = TestSignal::Sine(1000.0);
+        let samples = signal.generate(48000, 48000);
+        let report = analyze(&samples, false, signal.fundamental(), 48000);
+        assert!(report.snr_db > 60.0, "{report}");
+        assert!(report.thd_n_db.unwrap() < -60.0, "{report}");
+        let hd = analyze(&samples, true, signal.fundamental(), 48000);
+        assert!(hd.snr_db > report.snr_db, "{hd}");
+    }
+}
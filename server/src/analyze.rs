@@ -0,0 +1,122 @@
+#[allow(dead_code)]
+mod analysis;
+#[allow(dead_code)]
+mod wav;
+
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Signal {
+    Sine,
+    Multitone,
+    Noise,
+    Sweep,
+}
+
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Mode {
+    Aptx,
+    Hd,
+    Both,
+}
+
+/// Measures what aptX and aptX HD cost in quality on a WAV file or a test signal
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, clap::Parser)]
+struct Args {
+    /// WAV file to analyze, test signals are used when omitted
+    input: Option<std::path::PathBuf>,
+
+    #[arg(short, long, value_enum, default_value_t = Mode::Both)]
+    mode: Mode,
+
+    /// Test signal to use when no input is given, all of them by default
+    #[arg(short, long, value_enum)]
+    signal: Option<Signal>,
+
+    #[arg(long, default_value_t = 1000.0)]
+    freq: f64,
+
+    /// Fundamental of a sine WAV input, enables THD+N
+    #[arg(long)]
+    fundamental: Option<f64>,
+
+    #[arg(short, long, default_value_t = 2.0)]
+    duration: f64,
+
+    /// Write the decoded audio of the last run to this WAV file
+    #[arg(short, long)]
+    output: Option<std::path::PathBuf>,
+}
+
+#[cfg(not(target_os = "android"))]
+fn main() {
+    use analysis::TestSignal;
+    use clap::Parser;
+    use wav::{Wav, WavSpec};
+
+    let args = Args::parse();
+    let modes: &[bool] = match args.mode {
+        Mode::Aptx => &[false],
+        Mode::Hd => &[true],
+        Mode::Both => &[false, true],
+    };
+
+    let mut inputs = Vec::new();
+    let mut rate = 48000;
+    if let Some(path) = &args.input {
+        let wav = Wav::read(path).unwrap_or_else(|err| panic!("Fail to read {path:?}: {err}"));
+        rate = wav.spec.sample_rate;
+        let samples = wav.samples_i24();
+        let stereo: Vec<i32> = match wav.spec.channels {
+            1 => samples.iter().flat_map(|&s| [s, s]).collect(),
+            2 => samples,
+            n => samples
+                .chunks_exact(n as usize)
+                .flat_map(|frame| [frame[0], frame[1]])
+                .collect(),
+        };
+        inputs.push((path.display().to_string(), stereo, args.fundamental));
+    } else {
+        let frames = (args.duration * rate as f64) as usize;
+        let signals = match args.signal {
+            Some(Signal::Sine) => vec![TestSignal::Sine(args.freq)],
+            Some(Signal::Multitone) => vec![TestSignal::MultiTone],
+            Some(Signal::Noise) => vec![TestSignal::Noise],
+            Some(Signal::Sweep) => vec![TestSignal::Sweep],
+            None => vec![
+                TestSignal::Sine(args.freq),
+                TestSignal::MultiTone,
+                TestSignal::Noise,
+                TestSignal::Sweep,
+            ],
+        };
+        for signal in signals {
+            let samples = signal.generate(frames, rate);
+            inputs.push((signal.to_string(), samples, signal.fundamental()));
+        }
+    }
+
+    for (name, samples, fundamental) in &inputs {
+        println!("== {name}");
+        for &hd in modes {
+            println!("{}", analysis::analyze(samples, hd, *fundamental, rate));
+        }
+    }
+
+    if let (Some(path), Some((_, samples, _))) = (&args.output, inputs.last()) {
+        let hd = *modes.last().unwrap();
+        let (decoded, _) = analysis::roundtrip(samples, hd);
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: rate,
+            bits_per_sample: if hd { 24 } else { 16 },
+        };
+        Wav::from_samples_i24(spec, &decoded)
+            .write(path)
+            .unwrap_or_else(|err| panic!("Fail to write {path:?}: {err}"));
+    }
+}
+
+#[cfg(target_os = "android")]
+fn main() {}
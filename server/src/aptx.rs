@@ -19,6 +19,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this library.  If not, see <http://www.gnu.org/licenses/>.
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
 mod aptx_table;
 use aptx_table::{
     HD_INVERT_QUANTIZE_DITHER_FACTORS_HF, HD_INVERT_QUANTIZE_DITHER_FACTORS_LF,
@@ -36,7 +42,6 @@ use aptx_table::{
     QUANTIZE_INTERVALS_MHF, QUANTIZE_INTERVALS_MLF,
 };
 
-#[macro_export]
 macro_rules! diffsign {
     ($x:expr, $y:expr) => {
         (($x > $y) as i32 - ($x < $y) as i32)
@@ -54,7 +59,7 @@ const fn clip_intp2(a: i32, p: u32) -> i32 {
 
 #[inline]
 const fn sign_extend(val: i32, bits: u32) -> i32 {
-    let shift = 8 * std::mem::size_of::<i32>() as u32 - bits;
+    let shift = 8 * core::mem::size_of::<i32>() as u32 - bits;
     let v = ((val as u32) << shift) as i32;
     v >> shift
 }
@@ -98,7 +103,7 @@ const fn aptx_bin_search(value: i32, factor: i32, intervals: &[i32]) -> i32 {
     idx as i32
 }
 
-pub struct AptxTables {
+struct AptxTables {
     quantize_intervals: &'static [i32],
     invert_quantize_dither_factors: &'static [i32],
     quantize_dither_factors: &'static [i32],
@@ -171,6 +176,10 @@ struct AptxChannel {
     samples: [i32; Self::NB_SUBBANDS],
 }
 
+/// State of an aptX or aptX HD stream, usable either as an encoder or as a decoder.
+///
+/// Audio is stereo, 16 bit (aptX) or 24 bit (aptX HD) signed little endian, interleaved. Every
+/// group of 4 frames is coded as one codeword of 4 bytes (aptX) or 6 bytes (aptX HD).
 pub struct AptxContext {
     decode_sync_packets: usize,
     decode_dropped: usize,
@@ -191,8 +200,8 @@ impl Default for AptxContext {
             channels: Default::default(),
             hd: Default::default(),
             sync_idx: Default::default(),
-            encode_remaining: ((Self::LATENCY_SAMPLES + 3) / 4) as u8,
-            decode_skip_leading: ((Self::LATENCY_SAMPLES + 3) / 4) as u8,
+            encode_remaining: Self::LATENCY_SAMPLES.div_ceil(4) as u8,
+            decode_skip_leading: Self::LATENCY_SAMPLES.div_ceil(4) as u8,
             decode_sync_buffer_len: Default::default(),
             decode_sync_buffer: Default::default(),
         };
@@ -393,7 +402,7 @@ impl AptxQuantize {
             * (-((sample_difference < 0) as i32) | 1);
 
         let a = dither as i64 * interval as i64;
-        let b = ((clip_intp2(mean + d, 23) as i64) << 32) as i64;
+        let b = (clip_intp2(mean + d, 23) as i64) << 32;
         let dithered_sample = rshift64_clip24(a + b, 32);
         let error = ((sample_difference_abs as i64) << 20)
             - (dithered_sample as i64 * quantization_factor as i64);
@@ -714,15 +723,31 @@ impl AptxChannel {
 impl AptxContext {
     const LEFT: usize = 0;
     const RIGHT: usize = 1;
-    const LATENCY_SAMPLES: usize = 90;
-
+    /// Algorithmic delay in frames from the encoder input to the decoder output, for the
+    /// encoder and the decoder together. Only the sum shows outside the codec.
+    pub const LATENCY_SAMPLES: usize = 90;
+    /// Frames [`Self::decode`] drops at the start of a stream to compensate for the delay.
+    pub const DECODE_SKIP_FRAMES: usize = (Self::LATENCY_SAMPLES.div_ceil(4) - 1) * 4;
+    /// Delay left after the skip, decoded frame `n + DECODE_OFFSET` is encoder input frame `n`.
+    /// Whole codewords are skipped so this is not 0.
+    pub const DECODE_OFFSET: usize = Self::LATENCY_SAMPLES - Self::DECODE_SKIP_FRAMES;
+
+    /// Heap allocated context, the usual way to hold one since it is a few kilobytes large.
+    #[cfg(feature = "alloc")]
     pub fn new(hd: bool) -> Box<AptxContext> {
-        Box::new(AptxContext {
+        Box::new(Self::init(hd))
+    }
+
+    /// Context by value, for `no_std` targets without an allocator where it lives in a static
+    /// or on the stack.
+    pub fn init(hd: bool) -> AptxContext {
+        AptxContext {
             hd,
             ..Default::default()
-        })
+        }
     }
 
+    /// Returns the context to its initial state, keeping the aptX / aptX HD selection.
     pub fn reset(&mut self) {
         *self = AptxContext {
             hd: self.hd,
@@ -730,6 +755,28 @@ impl AptxContext {
         };
     }
 
+    /// Frames the decoder will still drop before its first output, 0 once the stream started.
+    pub fn decode_skip_remaining(&self) -> usize {
+        (self.decode_skip_leading as usize).saturating_sub(1) * 4
+    }
+
+    /// Whether the context codes aptX HD.
+    pub fn hd(&self) -> bool {
+        self.hd
+    }
+
+    /// Absolute quantization error of each subband (LF, MLF, MHF, HF) per channel, as left by
+    /// the last encoded codeword.
+    pub fn quantize_errors(&self) -> [[i32; AptxChannel::NB_SUBBANDS]; 2] {
+        let mut errors = [[0; AptxChannel::NB_SUBBANDS]; 2];
+        for (errors, channel) in errors.iter_mut().zip(&self.channels) {
+            for (error, quantize) in errors.iter_mut().zip(&channel.quantize) {
+                *error = quantize.error;
+            }
+        }
+        errors
+    }
+
     fn check_parity(&mut self) -> i32 {
         let parity = self.channels[Self::LEFT].quantized_parity()
             ^ self.channels[Self::RIGHT].quantized_parity();
@@ -821,10 +868,10 @@ impl AptxContext {
     /// # Parameters
     ///
     /// * `input` - A slice of bytes representing the input audio samples to be encoded.
-    ///             The input is expected to be in interleaved format, with each sample consisting of multiple bytes.
+    ///   The input is expected to be in interleaved format, with each sample consisting of multiple bytes.
     /// * `output` - A mutable slice of bytes where the encoded output will be stored.
     /// * `written` - A mutable reference to a `usize` variable that will be updated with the
-    ///               number of bytes written to the output buffer.
+    ///   number of bytes written to the output buffer.
     ///
     /// # Returns
     ///
@@ -833,6 +880,8 @@ impl AptxContext {
     /// # Example
     ///
     /// ```
+    /// use aptx_rust::aptx::AptxContext;
+    ///
     /// let mut encoder = AptxContext::new(false);
     /// let input_data: Vec<u8> = vec![0; 256*4];
     /// let mut output_data: Vec<u8> = vec![0; 256];
@@ -840,8 +889,8 @@ impl AptxContext {
     ///
     /// let bytes_read = encoder.encode(&input_data, &mut output_data, &mut bytes_written);
     ///
-    /// println!("Bytes read from input: {}", bytes_read);
-    /// println!("Bytes written to output: {}", bytes_written);
+    /// assert_eq!(bytes_read, input_data.len());
+    /// assert_eq!(bytes_written, output_data.len());
     /// ```
     ///
     pub fn encode(&mut self, input: &[u8], output: &mut [u8], written: &mut usize) -> usize {
@@ -875,6 +924,21 @@ impl AptxContext {
         ipos
     }
 
+    /// Flushes the codewords still held back by the codec delay, then resets the context.
+    ///
+    /// Returns 1 once everything was flushed and 0 when `output` was too small, in which case
+    /// it must be called again with more room.
+    ///
+    /// ```
+    /// use aptx_rust::aptx::AptxContext;
+    ///
+    /// let mut encoder = AptxContext::new(true);
+    /// let mut output = vec![0; 6 * AptxContext::LATENCY_SAMPLES];
+    /// let mut written = 0;
+    ///
+    /// assert_eq!(encoder.encode_finish(&mut output, &mut written), 1);
+    /// assert_eq!(written, 6 * AptxContext::LATENCY_SAMPLES.div_ceil(4));
+    /// ```
     pub fn encode_finish(&mut self, output: &mut [u8], written: &mut usize) -> i32 {
         let sample_size = if self.hd { 6 } else { 4 };
         let mut opos = 0;
@@ -907,11 +971,11 @@ impl AptxContext {
     /// # Parameters
     ///
     /// * `input` - A slice of bytes representing the input aptX encoded audio samples to be decoded.
-    ///             The input is expected to be in aptX format, with each sample consisting of multiple bytes.
+    ///   The input is expected to be in aptX format, with each sample consisting of multiple bytes.
     /// * `output` - A mutable slice of bytes where the decoded output will be stored.
-    ///              The output will be in interleaved format, with each sample consisting of multiple bytes.
+    ///   The output will be in interleaved format, with each sample consisting of multiple bytes.
     /// * `written` - A mutable reference to a `usize` variable that will be updated with the
-    ///               number of bytes written to the output buffer.
+    ///   number of bytes written to the output buffer.
     ///
     /// # Returns
     ///
@@ -919,16 +983,23 @@ impl AptxContext {
     ///
     /// # Example
     ///
+    /// The codewords covering the first [`AptxContext::LATENCY_SAMPLES`] frames are consumed
+    /// without output, so the decoded audio lines up with the input of the encoder.
+    ///
     /// ```
+    /// use aptx_rust::aptx::AptxContext;
+    ///
+    /// let mut encoder = AptxContext::new(false);
     /// let mut decoder = AptxContext::new(false);
-    /// let input_data: Vec<u8> = vec![0; 256];
+    /// let mut input_data: Vec<u8> = vec![0; 256];
     /// let mut output_data: Vec<u8> = vec![0; 256 * 4];
     /// let mut bytes_written: usize = 0;
     ///
+    /// encoder.encode(&output_data, &mut input_data, &mut bytes_written);
     /// let bytes_read = decoder.decode(&input_data, &mut output_data, &mut bytes_written);
     ///
-    /// println!("Bytes read from input: {}", bytes_read);
-    /// println!("Bytes written to output: {}", bytes_written);
+    /// assert_eq!(bytes_read, input_data.len());
+    /// assert!(bytes_written < output_data.len());
     /// ```
     ///
     pub fn decode(&mut self, input: &[u8], output: &mut [u8], written: &mut usize) -> usize {
@@ -972,6 +1043,30 @@ impl AptxContext {
         ipos
     }
 
+    /// Like [`AptxContext::decode`], but resynchronizes on corrupted or truncated input instead
+    /// of stopping, which is what a receiver reading from a lossy network needs. Partial
+    /// codewords are kept for the next call.
+    ///
+    /// `synced` is set when the decoder is locked on the stream and `dropped` to the number of
+    /// input bytes discarded while looking for the synchronization again.
+    ///
+    /// ```
+    /// use aptx_rust::aptx::AptxContext;
+    ///
+    /// let mut encoder = AptxContext::new(false);
+    /// let mut decoder = AptxContext::new(false);
+    /// let pcm = vec![0; 2048];
+    /// let mut encoded = vec![0; 512];
+    /// let mut decoded = vec![0; 2048];
+    /// let (mut written, mut synced, mut dropped) = (0, false, 0);
+    ///
+    /// encoder.encode(&pcm, &mut encoded, &mut written);
+    /// let read = decoder.decode_sync(&encoded, &mut decoded, &mut written, &mut synced, &mut dropped);
+    ///
+    /// assert_eq!(read, encoded.len());
+    /// assert!(synced);
+    /// assert_eq!(dropped, 0);
+    /// ```
     pub fn decode_sync(
         &mut self,
         input: &[u8],
@@ -1009,10 +1104,12 @@ impl AptxContext {
             self.decode_sync_buffer[sample_size - 1] = input[ipos];
             ipos += 1;
 
-            let decode_sync_buffer_copy = self.decode_sync_buffer[..sample_size].to_vec();
-            let processed_step =
-                self.decode(&decode_sync_buffer_copy, &mut output[opos..], written);
-            self.decode_sync_buffer[..sample_size].copy_from_slice(&decode_sync_buffer_copy);
+            let decode_sync_buffer_copy = self.decode_sync_buffer;
+            let processed_step = self.decode(
+                &decode_sync_buffer_copy[..sample_size],
+                &mut output[opos..],
+                written,
+            );
 
             opos += *written;
 
@@ -1111,6 +1208,8 @@ impl AptxContext {
         ipos
     }
 
+    /// Ends a [`AptxContext::decode_sync`] stream and resets the context, returning the number
+    /// of buffered bytes of an incomplete codeword that were dropped.
     pub fn decode_sync_finish(&mut self) -> usize {
         let dropped = self.decode_sync_buffer_len as usize;
         self.reset();
@@ -1118,6 +1217,256 @@ impl AptxContext {
     }
 }
 
+/// Why [`AptxContext::restore`] refused a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// Not [`AptxContext::SNAPSHOT_SIZE`] bytes long.
+    Size,
+    /// Not a snapshot of this version of the codec state.
+    Magic,
+    /// A position or length is out of range, the context is left untouched.
+    Corrupt,
+}
+
+impl core::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SnapshotError::Size => write!(f, "aptX snapshot has the wrong size"),
+            SnapshotError::Magic => write!(f, "Not an aptX snapshot"),
+            SnapshotError::Corrupt => write!(f, "aptX snapshot is corrupt"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SnapshotError {}
+
+/// Fixed layout writer and reader of snapshots, every value little endian.
+struct SnapshotWriter<'a> {
+    buffer: &'a mut [u8],
+    pos: usize,
+}
+
+impl SnapshotWriter<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buffer[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+struct SnapshotReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl SnapshotReader<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.buffer[self.pos..self.pos + N].try_into().unwrap();
+        self.pos += N;
+        bytes
+    }
+}
+
+/// Saves and loads one part of the state, in the same order.
+trait Snapshot {
+    fn save(&self, cursor: &mut SnapshotWriter);
+    fn load(&mut self, cursor: &mut SnapshotReader);
+}
+
+impl Snapshot for u8 {
+    fn save(&self, cursor: &mut SnapshotWriter) {
+        cursor.put(&[*self]);
+    }
+    fn load(&mut self, cursor: &mut SnapshotReader) {
+        *self = cursor.take::<1>()[0];
+    }
+}
+
+impl Snapshot for i32 {
+    fn save(&self, cursor: &mut SnapshotWriter) {
+        cursor.put(&self.to_le_bytes());
+    }
+    fn load(&mut self, cursor: &mut SnapshotReader) {
+        *self = i32::from_le_bytes(cursor.take());
+    }
+}
+
+impl Snapshot for usize {
+    fn save(&self, cursor: &mut SnapshotWriter) {
+        cursor.put(&(*self as u64).to_le_bytes());
+    }
+    fn load(&mut self, cursor: &mut SnapshotReader) {
+        *self = u64::from_le_bytes(cursor.take()) as usize;
+    }
+}
+
+impl<T: Snapshot, const N: usize> Snapshot for [T; N] {
+    fn save(&self, cursor: &mut SnapshotWriter) {
+        self.iter().for_each(|item| item.save(cursor));
+    }
+    fn load(&mut self, cursor: &mut SnapshotReader) {
+        self.iter_mut().for_each(|item| item.load(cursor));
+    }
+}
+
+macro_rules! impl_snapshot {
+    ($type:ty, $($field:ident),+) => {
+        impl Snapshot for $type {
+            fn save(&self, cursor: &mut SnapshotWriter) {
+                $(self.$field.save(cursor);)+
+            }
+            fn load(&mut self, cursor: &mut SnapshotReader) {
+                $(self.$field.load(cursor);)+
+            }
+        }
+    };
+}
+
+impl_snapshot!(AptxFilterSignal, buffer, pos);
+impl_snapshot!(AptxQmfAnalysis, outer_filter_signal, inner_filter_signal);
+impl_snapshot!(
+    AptxQuantize,
+    quantized_sample,
+    quantized_sample_parity_change,
+    error
+);
+impl_snapshot!(
+    AptxInvertQuantize,
+    quantization_factor,
+    factor_select,
+    reconstructed_difference
+);
+impl_snapshot!(
+    AptxPrediction,
+    prev_sign,
+    s_weight,
+    d_weight,
+    pos,
+    reconstructed_differences,
+    previous_reconstructed_sample,
+    predicted_difference,
+    predicted_sample
+);
+impl_snapshot!(
+    AptxChannel,
+    codeword_history,
+    dither_parity,
+    qmf,
+    dither,
+    quantize,
+    invert_quantize,
+    prediction,
+    samples
+);
+
+impl AptxContext {
+    const SNAPSHOT_MAGIC: [u8; 4] = *b"APX1";
+    const SNAPSHOT_HEADER: usize = 4 + 5 + 6 + 2 * 8;
+    const SNAPSHOT_SIGNAL: usize = 2 * AptxFilterSignal::FILTER_TAPS * 4 + 1;
+    const SNAPSHOT_PREDICTION: usize = (2 + 2 + 24 + 1 + 48 + 3) * 4;
+    const SNAPSHOT_CHANNEL: usize = 2 * 4
+        + 6 * Self::SNAPSHOT_SIGNAL
+        + AptxChannel::NB_SUBBANDS * (4 + 3 * 4 + 3 * 4 + Self::SNAPSHOT_PREDICTION + 4);
+    /// Size of a snapshot, the same for aptX and aptX HD.
+    pub const SNAPSHOT_SIZE: usize = Self::SNAPSHOT_HEADER + 2 * Self::SNAPSHOT_CHANNEL;
+
+    /// Complete state of the context: filter histories, predictors, quantizer factors and
+    /// stream position. [`AptxContext::restore`] on any context continues the stream exactly
+    /// where this one is, on another thread, after a restart or in a replay.
+    ///
+    /// ```
+    /// use aptx_rust::aptx::AptxContext;
+    ///
+    /// let mut encoder = AptxContext::new(false);
+    /// let mut encoded = [0u8; 64];
+    /// let mut written = 0;
+    /// encoder.encode(&[0x10; 256], &mut encoded, &mut written);
+    ///
+    /// let mut copy = AptxContext::new(false);
+    /// copy.restore(&encoder.snapshot()).unwrap();
+    /// let (mut a, mut b) = ([0u8; 64], [0u8; 64]);
+    /// encoder.encode(&[0x20; 256], &mut a, &mut written);
+    /// copy.encode(&[0x20; 256], &mut b, &mut written);
+    /// assert_eq!(a, b);
+    /// ```
+    pub fn snapshot(&self) -> [u8; Self::SNAPSHOT_SIZE] {
+        let mut snapshot = [0u8; Self::SNAPSHOT_SIZE];
+        let mut cursor = SnapshotWriter {
+            buffer: &mut snapshot,
+            pos: 0,
+        };
+        cursor.put(&Self::SNAPSHOT_MAGIC);
+        (self.hd as u8).save(&mut cursor);
+        self.sync_idx.save(&mut cursor);
+        self.encode_remaining.save(&mut cursor);
+        self.decode_skip_leading.save(&mut cursor);
+        self.decode_sync_buffer_len.save(&mut cursor);
+        self.decode_sync_buffer.save(&mut cursor);
+        self.decode_sync_packets.save(&mut cursor);
+        self.decode_dropped.save(&mut cursor);
+        self.channels.save(&mut cursor);
+        debug_assert_eq!(cursor.pos, Self::SNAPSHOT_SIZE);
+        snapshot
+    }
+
+    /// Replaces the state of the context, aptX or aptX HD included, with a
+    /// [`AptxContext::snapshot`].
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        if snapshot.len() != Self::SNAPSHOT_SIZE {
+            return Err(SnapshotError::Size);
+        }
+        if snapshot[..4] != Self::SNAPSHOT_MAGIC {
+            return Err(SnapshotError::Magic);
+        }
+        let mut cursor = SnapshotReader {
+            buffer: snapshot,
+            pos: 4,
+        };
+        let mut ctx = AptxContext::default();
+        let mut hd = 0u8;
+        hd.load(&mut cursor);
+        ctx.hd = hd != 0;
+        ctx.sync_idx.load(&mut cursor);
+        ctx.encode_remaining.load(&mut cursor);
+        ctx.decode_skip_leading.load(&mut cursor);
+        ctx.decode_sync_buffer_len.load(&mut cursor);
+        ctx.decode_sync_buffer.load(&mut cursor);
+        ctx.decode_sync_packets.load(&mut cursor);
+        ctx.decode_dropped.load(&mut cursor);
+        ctx.channels.load(&mut cursor);
+
+        // Positions index into buffers, anything out of range would panic later on
+        let tables = &AptxChannel::ALL_TABLES[ctx.hd as usize];
+        let valid = hd <= 1
+            && ctx.sync_idx < 8
+            && ctx.decode_sync_buffer_len < if ctx.hd { 6 } else { 4 }
+            && ctx.channels.iter().all(|channel| {
+                let signals = channel.qmf.outer_filter_signal.iter().chain(
+                    channel
+                        .qmf
+                        .inner_filter_signal
+                        .iter()
+                        .flat_map(|signals| signals.iter()),
+                );
+                signals
+                    .into_iter()
+                    .all(|signal| (signal.pos as usize) < AptxFilterSignal::FILTER_TAPS)
+                    && channel
+                        .prediction
+                        .iter()
+                        .zip(tables)
+                        .all(|(prediction, tables)| {
+                            (0..tables.prediction_order).contains(&prediction.pos)
+                        })
+            });
+        if !valid {
+            return Err(SnapshotError::Corrupt);
+        }
+        *self = ctx;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1174,7 +1523,7 @@ mod tests {
             ..Default::default()
         };
         channel.generate_dither();
-        assert_eq!(channel.dither[0], -209715200); 
+        assert_eq!(channel.dither[0], -209715200);
     }
 
     #[test]
@@ -1214,7 +1563,7 @@ mod tests {
         let quantization_factor = 2048;
         let tables = &AptxChannel::ALL_TABLES[0][0];
         quantize.quantize_difference(sample_difference, dither, quantization_factor, tables);
-        assert_eq!(quantize.quantized_sample, 12); 
+        assert_eq!(quantize.quantized_sample, 12);
     }
 
     #[test]
@@ -1224,7 +1573,7 @@ mod tests {
             ..Default::default()
         };
         channel.encode_channel(false);
-        assert_eq!(channel.quantize[0].quantized_sample, 63); 
+        assert_eq!(channel.quantize[0].quantized_sample, 63);
     }
 
     #[test]
@@ -1232,7 +1581,7 @@ mod tests {
         let mut channel = AptxChannel::default();
         channel.prediction[0].previous_reconstructed_sample = 1000;
         channel.decode_channel();
-        assert_eq!(channel.samples[0], 0); 
+        assert_eq!(channel.samples[0], 0);
     }
 
     #[test]
@@ -1259,7 +1608,7 @@ mod tests {
             ..Default::default()
         };
         let result = channel.pack_codeword();
-        assert_eq!(result, 39169); 
+        assert_eq!(result, 39169);
     }
 
     #[test]
@@ -1279,11 +1628,11 @@ mod tests {
         assert!(!ctx.hd);
         assert_eq!(
             ctx.decode_skip_leading,
-            ((AptxContext::LATENCY_SAMPLES + 3) / 4) as u8
+            AptxContext::LATENCY_SAMPLES.div_ceil(4) as u8
         );
         assert_eq!(
             ctx.encode_remaining,
-            ((AptxContext::LATENCY_SAMPLES + 3) / 4) as u8
+            AptxContext::LATENCY_SAMPLES.div_ceil(4) as u8
         );
     }
 
@@ -1294,11 +1643,11 @@ mod tests {
         assert!(!ctx.hd);
         assert_eq!(
             ctx.decode_skip_leading,
-            ((AptxContext::LATENCY_SAMPLES + 3) / 4) as u8
+            AptxContext::LATENCY_SAMPLES.div_ceil(4) as u8
         );
         assert_eq!(
             ctx.encode_remaining,
-            ((AptxContext::LATENCY_SAMPLES + 3) / 4) as u8
+            AptxContext::LATENCY_SAMPLES.div_ceil(4) as u8
         );
     }
 
@@ -1344,7 +1693,7 @@ mod tests {
         let read = ctx.decode_sync(&input, &mut output, &mut written, &mut synced, &mut dropped);
         assert_eq!(read, input.len());
         assert!(written == 0);
-        assert_eq!(synced, true);
+        assert!(synced);
         assert_eq!(dropped, 0);
     }
 
@@ -1355,4 +1704,92 @@ mod tests {
         let dropped = ctx.decode_sync_finish();
         assert_eq!(dropped, 5);
     }
+
+    #[test]
+    fn test_aptx_decode_offset() {
+        for hd in [false, true] {
+            let sample_size = if hd { 3 } else { 2 };
+            let mut encoder = AptxContext::init(hd);
+            let mut decoder = AptxContext::init(hd);
+            assert_eq!(
+                decoder.decode_skip_remaining(),
+                AptxContext::DECODE_SKIP_FRAMES
+            );
+
+            let impulse = 40;
+            let mut pcm = vec![0u8; 400 * 2 * sample_size];
+            pcm[impulse * 2 * sample_size + sample_size - 1] = 0x40;
+            let mut encoded = vec![0u8; 100 * 2 * sample_size];
+            let mut decoded = vec![0u8; pcm.len()];
+            let mut written = 0;
+            encoder.encode(&pcm, &mut encoded, &mut written);
+            decoder.decode(&encoded, &mut decoded, &mut written);
+            assert_eq!(decoder.decode_skip_remaining(), 0);
+
+            let peak = decoded[..written]
+                .chunks_exact(2 * sample_size)
+                .map(|frame| {
+                    let mut sample = [0u8; 4];
+                    sample[4 - sample_size..].copy_from_slice(&frame[..sample_size]);
+                    (i32::from_le_bytes(sample) >> 8).abs()
+                })
+                .enumerate()
+                .max_by_key(|&(_, level)| level)
+                .unwrap()
+                .0;
+            assert_eq!(peak, impulse + AptxContext::DECODE_OFFSET);
+        }
+    }
+
+    #[test]
+    fn test_aptx_snapshot_restore() {
+        let mut encoder = AptxContext::init(true);
+        let pcm: [u8; 24 * 64] = core::array::from_fn(|i| (i * 7) as u8);
+        let mut encoded = [0u8; 6 * 64];
+        let mut written = 0;
+        encoder.encode(&pcm, &mut encoded, &mut written);
+
+        // Start mid-stream, on a codeword that is not aligned to the sync sequence
+        let mut decoder = AptxContext::init(true);
+        let (mut synced, mut dropped) = (false, 0);
+        let mut decoded = [0u8; 24 * 64];
+        decoder.decode_sync(
+            &encoded[..6 * 37 + 2],
+            &mut decoded,
+            &mut written,
+            &mut synced,
+            &mut dropped,
+        );
+        let mut restored = AptxContext::init(false);
+        restored.restore(&decoder.snapshot()).unwrap();
+        assert!(restored.hd());
+        assert_eq!(restored.snapshot(), decoder.snapshot());
+
+        let mut expected = [0u8; 24 * 64];
+        let mut expected_written = 0;
+        let rest = &encoded[6 * 37 + 2..];
+        decoder.decode_sync(
+            rest,
+            &mut expected,
+            &mut expected_written,
+            &mut synced,
+            &mut dropped,
+        );
+        restored.decode_sync(rest, &mut decoded, &mut written, &mut synced, &mut dropped);
+        assert_eq!(written, expected_written);
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_aptx_snapshot_invalid() {
+        let mut ctx = AptxContext::init(false);
+        let mut snapshot = ctx.snapshot();
+        assert_eq!(ctx.restore(&snapshot[1..]), Err(SnapshotError::Size));
+        snapshot[0] = 0;
+        assert_eq!(ctx.restore(&snapshot), Err(SnapshotError::Magic));
+        let mut snapshot = ctx.snapshot();
+        // sync_idx
+        snapshot[5] = 8;
+        assert_eq!(ctx.restore(&snapshot), Err(SnapshotError::Corrupt));
+    }
 }
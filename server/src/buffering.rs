@@ -0,0 +1,260 @@
+//! How much audio each end of a stream keeps queued. A [`Profile`] picks a consistent
+//! [`Buffering`] for both the server and the receiver, the packet size in particular has to
+//! match on both ends. Every value can be overridden on its own.
+//!
+//! Only `server_pulse` and `listen` take a profile. The Android receiver keeps fixed buffers,
+//! receive buffers for the largest packet and a low latency AudioTrack sized by the system.
+
+use crate::{
+    adapt::LADDER,
+    protocol::{CONTROL_MAX_LEN, SAMPLE_RATE},
+};
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+/// Largest packet the receivers take, a PCM packet of this many frames fills their 4096 byte
+/// receive buffers.
+pub const MAX_PACKET_FRAMES: usize = 1024;
+/// Smallest packet, every codec's packet of this many frames is longer than a control packet.
+/// aptX at 24 kHz packs two frames in a byte.
+const MIN_PACKET_FRAMES: usize = 2 * CONTROL_MAX_LEN + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Wired or quiet networks, underruns as soon as packets are a few ms late
+    UltraLow,
+    /// Packets of about 10 ms and a few of them buffered, the default
+    Balanced,
+    /// Busy Wi-Fi, rides out bursts of delay at the cost of ~100 ms
+    Robust,
+}
+
+impl Profile {
+    pub fn buffering(self) -> Buffering {
+        let (packet_frames, capture_packets, playback_packets) = match self {
+            Profile::UltraLow => (256, 2, 2),
+            Profile::Balanced => (512, 4, 3),
+            Profile::Robust => (1024, 16, 8),
+        };
+        Buffering {
+            packet_frames,
+            capture_packets,
+            playback_packets,
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Profile::UltraLow => write!(f, "ultra-low"),
+            Profile::Balanced => write!(f, "balanced"),
+            Profile::Robust => write!(f, "robust"),
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ultra-low" | "ultralow" | "low" => Ok(Profile::UltraLow),
+            "balanced" => Ok(Profile::Balanced),
+            "robust" => Ok(Profile::Robust),
+            other => Err(format!(
+                "Unknown profile {other}, expected ultra-low, balanced or robust"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffering {
+    /// Frames per packet, the server captures and sends one packet at a time
+    pub packet_frames: usize,
+    /// Audio the server's capture holds before dropping it, in packets
+    pub capture_packets: usize,
+    /// Audio the receiver keeps queued for playback, in packets
+    pub playback_packets: usize,
+}
+
+impl Buffering {
+    pub fn validate(&self) -> Result<(), String> {
+        // Every codec the stream can switch to has to code whole packets
+        let block = LADDER
+            .iter()
+            .map(|codec| codec.block_frames())
+            .max()
+            .unwrap_or(1);
+        if !self.packet_frames.is_multiple_of(block)
+            || !(MIN_PACKET_FRAMES..=MAX_PACKET_FRAMES).contains(&self.packet_frames)
+        {
+            return Err(format!(
+                "Packets must be a multiple of {block} frames between {MIN_PACKET_FRAMES} and \
+                 {MAX_PACKET_FRAMES}, not {}",
+                self.packet_frames
+            ));
+        }
+        if self.capture_packets == 0 || self.playback_packets == 0 {
+            return Err("Buffers must hold at least one packet".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn packet_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.packet_frames as f64 / SAMPLE_RATE as f64)
+    }
+
+    /// Packets lasting `duration`, rounded up, at least one.
+    pub fn packets_in(&self, duration: Duration) -> usize {
+        let packets = duration.as_secs_f64() / self.packet_duration().as_secs_f64();
+        (packets.ceil() as usize).max(1)
+    }
+
+    /// Attributes of the server's record stream, for frames of `bytes_per_frame`. PulseAudio
+    /// hands over one packet at a time and drops what the server does not read in time.
+    pub fn capture_attr(&self, bytes_per_frame: usize) -> PulseBuffer {
+        let packet = (self.packet_frames * bytes_per_frame) as u32;
+        PulseBuffer {
+            maxlength: packet * self.capture_packets as u32,
+            tlength: packet,
+            prebuf: packet / 4,
+            minreq: packet / 4,
+            fragsize: packet,
+        }
+    }
+
+    /// Attributes of the receiver's playback stream, for frames of `bytes_per_frame`. Playback
+    /// starts after one packet and the server keeps the rest of the buffer filled.
+    pub fn playback_attr(&self, bytes_per_frame: usize) -> PulseBuffer {
+        let packet = (self.packet_frames * bytes_per_frame) as u32;
+        let target = packet * self.playback_packets as u32;
+        PulseBuffer {
+            maxlength: target * 4,
+            tlength: target,
+            prebuf: packet,
+            minreq: packet / 4,
+            fragsize: packet,
+        }
+    }
+}
+
+/// PulseAudio buffer attributes in bytes, see `pa_buffer_attr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PulseBuffer {
+    pub maxlength: u32,
+    pub tlength: u32,
+    pub prebuf: u32,
+    pub minreq: u32,
+    pub fragsize: u32,
+}
+
+/// Value of `--pulse-buffer`, attributes set by hand as `name=bytes[,name=bytes...]`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PulseOverrides {
+    pub maxlength: Option<u32>,
+    pub tlength: Option<u32>,
+    pub prebuf: Option<u32>,
+    pub minreq: Option<u32>,
+    pub fragsize: Option<u32>,
+}
+
+impl PulseOverrides {
+    pub fn apply(&self, attr: PulseBuffer) -> PulseBuffer {
+        PulseBuffer {
+            maxlength: self.maxlength.unwrap_or(attr.maxlength),
+            tlength: self.tlength.unwrap_or(attr.tlength),
+            prebuf: self.prebuf.unwrap_or(attr.prebuf),
+            minreq: self.minreq.unwrap_or(attr.minreq),
+            fragsize: self.fragsize.unwrap_or(attr.fragsize),
+        }
+    }
+}
+
+impl FromStr for PulseOverrides {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut overrides = PulseOverrides::default();
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let (name, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("Expected name=bytes, not {item}"))?;
+            let value = Some(
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid size {value} for {name}"))?,
+            );
+            match name.trim() {
+                "maxlength" => overrides.maxlength = value,
+                "tlength" => overrides.tlength = value,
+                "prebuf" => overrides.prebuf = value,
+                "minreq" => overrides.minreq = value,
+                "fragsize" => overrides.fragsize = value,
+                other => {
+                    return Err(format!(
+                        "Unknown attribute {other}, expected maxlength, tlength, prebuf, \
+                         minreq or fragsize"
+                    ))
+                }
+            }
+        }
+        Ok(overrides)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        for profile in [Profile::UltraLow, Profile::Balanced, Profile::Robust] {
+            assert_eq!(profile.to_string().parse(), Ok(profile));
+            assert_eq!(profile.buffering().validate(), Ok(()));
+        }
+        assert!("fast".parse::<Profile>().is_err());
+        let balanced = Profile::Balanced.buffering();
+        assert_eq!(balanced.packets_in(Duration::from_millis(40)), 4);
+        assert_eq!(balanced.packets_in(Duration::ZERO), 1);
+        let attr = balanced.playback_attr(4);
+        assert_eq!((attr.tlength, attr.prebuf), (6144, 2048));
+        assert_eq!(balanced.capture_attr(6).fragsize, 3072);
+        let odd = Buffering {
+            packet_frames: 510,
+            ..balanced
+        };
+        assert!(odd.validate().is_err());
+    }
+
+    #[test]
+    fn test_min_packet_longer_than_control() {
+        for codec in LADDER {
+            assert!(
+                codec.packet_size(MIN_PACKET_FRAMES) > CONTROL_MAX_LEN,
+                "{codec}"
+            );
+        }
+        let smallest = Buffering {
+            packet_frames: MIN_PACKET_FRAMES,
+            ..Profile::UltraLow.buffering()
+        };
+        assert_eq!(smallest.validate(), Ok(()));
+        let control_sized = Buffering {
+            packet_frames: 2 * CONTROL_MAX_LEN,
+            ..smallest
+        };
+        assert!(control_sized.validate().is_err());
+    }
+
+    #[test]
+    fn test_pulse_overrides() {
+        let overrides: PulseOverrides = "tlength=4096, prebuf=1024".parse().unwrap();
+        let attr = overrides.apply(Profile::Balanced.buffering().playback_attr(4));
+        assert_eq!((attr.tlength, attr.prebuf, attr.minreq), (4096, 1024, 512));
+        assert!("tlength".parse::<PulseOverrides>().is_err());
+        assert!("latency=20".parse::<PulseOverrides>().is_err());
+        assert!("tlength=-1".parse::<PulseOverrides>().is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! C interface mirroring libopenaptx (`openaptx.h`), so existing C callers can link against
+//! this crate instead. The header is generated into `include/openaptx.h` by cbindgen.
+
+use crate::aptx::AptxContext;
+use std::{
+    ffi::{c_int, c_uchar},
+    slice,
+};
+
+/// Version of the libopenaptx API implemented.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static aptx_major: c_int = 0;
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static aptx_minor: c_int = 2;
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static aptx_patch: c_int = 1;
+
+unsafe fn input_slice<'a>(input: *const c_uchar, size: usize) -> &'a [u8] {
+    if input.is_null() || size == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(input, size)
+    }
+}
+
+unsafe fn output_slice<'a>(output: *mut c_uchar, size: usize) -> &'a mut [u8] {
+    if output.is_null() || size == 0 {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(output, size)
+    }
+}
+
+unsafe fn set<T>(dest: *mut T, value: T) {
+    if !dest.is_null() {
+        *dest = value;
+    }
+}
+
+/// Allocates a context for aptX (`hd` = 0) or aptX HD (`hd` != 0), to be freed with
+/// `aptx_finish`.
+#[no_mangle]
+pub extern "C" fn aptx_init(hd: c_int) -> *mut AptxContext {
+    Box::into_raw(AptxContext::new(hd != 0))
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init` and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn aptx_reset(ctx: *mut AptxContext) {
+    if let Some(ctx) = ctx.as_mut() {
+        ctx.reset();
+    }
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init` or be NULL, it is invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn aptx_finish(ctx: *mut AptxContext) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx));
+    }
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init`, `input` and `output` must be valid for `input_size` and
+/// `output_size` bytes, `written` must be valid or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn aptx_encode(
+    ctx: *mut AptxContext,
+    input: *const c_uchar,
+    input_size: usize,
+    output: *mut c_uchar,
+    output_size: usize,
+    written: *mut usize,
+) -> usize {
+    let Some(ctx) = ctx.as_mut() else {
+        set(written, 0);
+        return 0;
+    };
+    let mut out_written = 0;
+    let processed = ctx.encode(
+        input_slice(input, input_size),
+        output_slice(output, output_size),
+        &mut out_written,
+    );
+    set(written, out_written);
+    processed
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init`, `output` must be valid for `output_size` bytes, `written`
+/// must be valid or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn aptx_encode_finish(
+    ctx: *mut AptxContext,
+    output: *mut c_uchar,
+    output_size: usize,
+    written: *mut usize,
+) -> c_int {
+    let Some(ctx) = ctx.as_mut() else {
+        set(written, 0);
+        return 1;
+    };
+    let mut out_written = 0;
+    let ret = ctx.encode_finish(output_slice(output, output_size), &mut out_written);
+    set(written, out_written);
+    ret
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init`, `input` and `output` must be valid for `input_size` and
+/// `output_size` bytes, `written` must be valid or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn aptx_decode(
+    ctx: *mut AptxContext,
+    input: *const c_uchar,
+    input_size: usize,
+    output: *mut c_uchar,
+    output_size: usize,
+    written: *mut usize,
+) -> usize {
+    let Some(ctx) = ctx.as_mut() else {
+        set(written, 0);
+        return 0;
+    };
+    let mut out_written = 0;
+    let processed = ctx.decode(
+        input_slice(input, input_size),
+        output_slice(output, output_size),
+        &mut out_written,
+    );
+    set(written, out_written);
+    processed
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init`, `input` and `output` must be valid for `input_size` and
+/// `output_size` bytes, `written`, `synced` and `dropped` must be valid or NULL.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn aptx_decode_sync(
+    ctx: *mut AptxContext,
+    input: *const c_uchar,
+    input_size: usize,
+    output: *mut c_uchar,
+    output_size: usize,
+    written: *mut usize,
+    synced: *mut c_int,
+    dropped: *mut usize,
+) -> usize {
+    let Some(ctx) = ctx.as_mut() else {
+        set(written, 0);
+        set(synced, 0);
+        set(dropped, 0);
+        return 0;
+    };
+    let (mut out_written, mut out_synced, mut out_dropped) = (0, false, 0);
+    let processed = ctx.decode_sync(
+        input_slice(input, input_size),
+        output_slice(output, output_size),
+        &mut out_written,
+        &mut out_synced,
+        &mut out_dropped,
+    );
+    set(written, out_written);
+    set(synced, out_synced as c_int);
+    set(dropped, out_dropped);
+    processed
+}
+
+/// # Safety
+///
+/// `ctx` must come from `aptx_init`.
+#[no_mangle]
+pub unsafe extern "C" fn aptx_decode_sync_finish(ctx: *mut AptxContext) -> usize {
+    ctx.as_mut().map_or(0, |ctx| ctx.decode_sync_finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn test_header_up_to_date() {
+        let generated = concat!(env!("OUT_DIR"), "/openaptx.h");
+        assert!(
+            include_str!(concat!(env!("OUT_DIR"), "/openaptx.h"))
+                == include_str!("../include/openaptx.h"),
+            "include/openaptx.h is out of date, copy {generated} over it"
+        );
+    }
+
+    #[test]
+    fn test_capi_roundtrip() {
+        unsafe {
+            let encoder = aptx_init(1);
+            let decoder = aptx_init(1);
+            let pcm = [0u8; 24 * 64];
+            let mut encoded = [0u8; 6 * 64];
+            let mut decoded = [0u8; 24 * 64];
+            let (mut written, mut synced, mut dropped) = (0, 0, 0);
+
+            let read = aptx_encode(
+                encoder,
+                pcm.as_ptr(),
+                pcm.len(),
+                encoded.as_mut_ptr(),
+                encoded.len(),
+                &mut written,
+            );
+            assert_eq!(read, pcm.len());
+            assert_eq!(written, encoded.len());
+
+            let read = aptx_decode_sync(
+                decoder,
+                encoded.as_ptr(),
+                encoded.len(),
+                decoded.as_mut_ptr(),
+                decoded.len(),
+                &mut written,
+                &mut synced,
+                &mut dropped,
+            );
+            assert_eq!(read, encoded.len());
+            assert_eq!(synced, 1);
+            assert_eq!(dropped, 0);
+            assert_eq!(aptx_decode_sync_finish(decoder), 0);
+
+            aptx_finish(encoder);
+            aptx_finish(decoder);
+        }
+    }
+
+    #[test]
+    fn test_capi_null_context() {
+        unsafe {
+            let mut written = 1;
+            assert_eq!(
+                aptx_encode(
+                    ptr::null_mut(),
+                    ptr::null(),
+                    0,
+                    ptr::null_mut(),
+                    0,
+                    &mut written
+                ),
+                0
+            );
+            assert_eq!(written, 0);
+            aptx_reset(ptr::null_mut());
+            aptx_finish(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,556 @@
+//! JACK clients, for machines running JACK with fixed low-latency periods. The server exposes
+//! two input ports and relays whatever is connected to them, `listen` exposes two output ports
+//! and connects them to the physical outputs.
+//!
+//! The codec runs in the process callbacks. The server's [`Transmitter`] and the receiver's
+//! [`StreamDecoder`] are owned by the handlers and never allocate once built, codec and volume
+//! changes reach them through the [`StreamSettings`] atomics or the control packets queued with
+//! the audio. Packets cross to the network threads through the lock-free [`ring`](crate::ring),
+//! whose blocking side parks until the callback wakes it. A client the JACK server shut down
+//! fails its blocking calls rather than waiting for a callback that no longer runs.
+
+use crate::ring::{ring, RingReader, RingWriter};
+use aptx_rust::{
+    protocol::{Codec, Control},
+    stream::{Outgoing, Packet, StreamDecoder, StreamSettings, Transmitter},
+};
+use jack::{
+    AudioIn, AudioOut, Client, ClientOptions, ClientStatus, NotificationHandler, Port, PortFlags,
+    ProcessHandler, ProcessScope,
+};
+use log::{info, warn};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+const SAMPLE_RATE: usize = 48000;
+const CHANNELS: usize = 2;
+/// The stream's samples, 24 bit.
+const BYTES_PER_SAMPLE: usize = 3;
+/// Packets the ring holds between the graph and the network side.
+const QUEUE_PACKETS: usize = 4;
+/// Largest packet `listen` receives.
+const MAX_PACKET: usize = 4096;
+/// What precedes a captured packet in the ring: what became of it, a codec, the length of the
+/// encoded audio and a count of silent packets.
+const ENTRY_HEADER: usize = 8;
+
+fn jack_error(err: jack::Error) -> io::Error {
+    io::Error::other(format!("JACK: {err}"))
+}
+
+/// Raised when the JACK server shuts the client down, the process callback is not called again.
+struct Shutdown(Arc<AtomicBool>);
+
+impl NotificationHandler for Shutdown {
+    fn shutdown(&mut self, _: ClientStatus, _: &str) {
+        // Runs like a signal handler, nothing but the flag
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+fn check_shutdown(shutdown: &AtomicBool) -> io::Result<()> {
+    if shutdown.load(Ordering::Acquire) {
+        return Err(io::Error::other("JACK server shut the client down"));
+    }
+    Ok(())
+}
+
+fn open_client(name: &str) -> io::Result<Client> {
+    let (client, _) = Client::new(name, ClientOptions::NO_START_SERVER).map_err(jack_error)?;
+    if client.sample_rate() != SAMPLE_RATE {
+        return Err(io::Error::other(format!(
+            "JACK runs at {} Hz, the stream needs {SAMPLE_RATE} Hz",
+            client.sample_rate()
+        )));
+    }
+    info!(
+        "JACK client {}, {} frames per period",
+        client.name(),
+        client.buffer_size()
+    );
+    Ok(client)
+}
+
+fn frames_duration(frames: usize) -> Duration {
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
+fn to_sample(value: f32, bytes_per_sample: usize) -> [u8; 4] {
+    let scale = if bytes_per_sample == 3 {
+        8388607.0
+    } else {
+        32767.0
+    };
+    ((value.clamp(-1.0, 1.0) * scale) as i32).to_le_bytes()
+}
+
+fn from_sample(bytes: &[u8]) -> f32 {
+    match *bytes {
+        [a, b, c] => (i32::from_le_bytes([0, a, b, c]) >> 8) as f32 / 8388608.0,
+        [a, b] => i16::from_le_bytes([a, b]) as f32 / 32768.0,
+        _ => 0.0,
+    }
+}
+
+fn entry_header(outgoing: Outgoing, len: usize) -> [u8; ENTRY_HEADER] {
+    let (kind, codec, count) = match outgoing {
+        // The transmitter only marks switches and resumes
+        Outgoing::Audio {
+            marker,
+            resumed_after,
+        } => match marker {
+            Some(Control::Codec(codec)) => (1, codec.id(), resumed_after),
+            Some(Control::Resume(codec)) => (2, codec.id(), resumed_after),
+            _ => (0, 0, resumed_after),
+        },
+        Outgoing::Silence { packets } => (3, 0, packets),
+    };
+    let mut header = [kind, codec, 0, 0, 0, 0, 0, 0];
+    header[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+    header[4..].copy_from_slice(&(count as u32).to_le_bytes());
+    header
+}
+
+fn parse_entry_header(header: [u8; ENTRY_HEADER]) -> (Outgoing, usize) {
+    let codec = Codec::from_id(header[1]).unwrap_or(Codec::Pcm);
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let audio = |marker| Outgoing::Audio {
+        marker,
+        resumed_after: count,
+    };
+    let outgoing = match header[0] {
+        1 => audio(Some(Control::Codec(codec))),
+        2 => audio(Some(Control::Resume(codec))),
+        3 => Outgoing::Silence { packets: count },
+        _ => audio(None),
+    };
+    (outgoing, len)
+}
+
+/// Cuts the captured frames into packets and queues what the transmitter makes of them.
+struct CaptureEncoder {
+    transmitter: Transmitter,
+    settings: Arc<StreamSettings>,
+    /// The packet being captured, interleaved 24 bit
+    pcm: Vec<u8>,
+    filled: usize,
+    packet: Vec<u8>,
+    ring: RingWriter,
+    queued: Arc<AtomicUsize>,
+    overruns: Arc<AtomicUsize>,
+}
+
+impl CaptureEncoder {
+    /// Adds a frame, returns whether it completed a packet.
+    fn push(&mut self, left: f32, right: f32) -> bool {
+        let frame = &mut self.pcm[self.filled..self.filled + CHANNELS * BYTES_PER_SAMPLE];
+        frame[..BYTES_PER_SAMPLE]
+            .copy_from_slice(&to_sample(left, BYTES_PER_SAMPLE)[..BYTES_PER_SAMPLE]);
+        frame[BYTES_PER_SAMPLE..]
+            .copy_from_slice(&to_sample(right, BYTES_PER_SAMPLE)[..BYTES_PER_SAMPLE]);
+        self.filled += frame.len();
+        if self.filled < self.pcm.len() {
+            return false;
+        }
+        self.filled = 0;
+        let outgoing = self
+            .transmitter
+            .process(&mut self.pcm, &self.settings, &mut self.packet);
+        let payload: &[u8] = match outgoing {
+            Outgoing::Audio { .. } => &self.packet,
+            Outgoing::Silence { .. } => &[],
+        };
+        // Whole packets only, the network side is late if they do not fit
+        if self.ring.space() < ENTRY_HEADER + payload.len() {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.ring.write(&entry_header(outgoing, payload.len()));
+        self.ring.write(payload);
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+struct CaptureHandler {
+    left: Port<AudioIn>,
+    right: Port<AudioIn>,
+    encoder: CaptureEncoder,
+}
+
+impl ProcessHandler for CaptureHandler {
+    fn process(&mut self, _: &Client, scope: &ProcessScope) -> jack::Control {
+        let (left, right) = (self.left.as_slice(scope), self.right.as_slice(scope));
+        let mut queued = false;
+        for (&l, &r) in left.iter().zip(right) {
+            queued |= self.encoder.push(l, r);
+        }
+        if queued {
+            self.encoder.ring.wake();
+        }
+        jack::Control::Continue
+    }
+}
+
+pub struct JackCapture {
+    _client: jack::AsyncClient<Shutdown, CaptureHandler>,
+    ring: RingReader,
+    frames_per_packet: usize,
+    period: Duration,
+    queued: Arc<AtomicUsize>,
+    overruns: Arc<AtomicUsize>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl JackCapture {
+    /// Registers the `in_left` / `in_right` ports of the client `name`, whose process callback
+    /// runs the captured audio through `transmitter` `frames_per_packet` frames at a time.
+    pub fn new(
+        name: &str,
+        frames_per_packet: usize,
+        transmitter: Transmitter,
+        settings: Arc<StreamSettings>,
+    ) -> io::Result<JackCapture> {
+        let client = open_client(name)?;
+        let period = frames_duration(client.buffer_size() as usize);
+        let left = client
+            .register_port("in_left", AudioIn)
+            .map_err(jack_error)?;
+        let right = client
+            .register_port("in_right", AudioIn)
+            .map_err(jack_error)?;
+        let (writer, ring) =
+            ring(QUEUE_PACKETS * (ENTRY_HEADER + Codec::Pcm.packet_size(frames_per_packet)));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let overruns = Arc::new(AtomicUsize::new(0));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let handler = CaptureHandler {
+            left,
+            right,
+            encoder: CaptureEncoder {
+                transmitter,
+                settings,
+                pcm: vec![0; frames_per_packet * CHANNELS * BYTES_PER_SAMPLE],
+                filled: 0,
+                packet: Vec::with_capacity(Codec::Pcm.packet_size(frames_per_packet)),
+                ring: writer,
+                queued: queued.clone(),
+                overruns: overruns.clone(),
+            },
+        };
+        let client = client
+            .activate_async(Shutdown(shutdown.clone()), handler)
+            .map_err(jack_error)?;
+        Ok(JackCapture {
+            _client: client,
+            ring,
+            frames_per_packet,
+            period,
+            queued,
+            overruns,
+            shutdown,
+        })
+    }
+
+    fn receive(&mut self, output: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        loop {
+            filled += self.ring.read(&mut output[filled..]);
+            if filled == output.len() {
+                return Ok(());
+            }
+            check_shutdown(&self.shutdown)?;
+            self.ring.wait();
+        }
+    }
+
+    /// Blocks until the callback queued a packet, leaves its encoded audio in `packet`.
+    pub fn next(&mut self, packet: &mut Vec<u8>) -> io::Result<Outgoing> {
+        let mut header = [0; ENTRY_HEADER];
+        self.receive(&mut header)?;
+        let (outgoing, len) = parse_entry_header(header);
+        packet.resize(len, 0);
+        self.receive(packet)?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        let overruns = self.overruns.swap(0, Ordering::Relaxed);
+        if overruns > 0 {
+            warn!("JACK: dropped {overruns} packets");
+        }
+        Ok(outgoing)
+    }
+
+    /// Packets in the ring, plus the period being recorded.
+    pub fn latency(&self) -> Duration {
+        frames_duration(self.queued.load(Ordering::Relaxed) * self.frames_per_packet) + self.period
+    }
+}
+
+/// Decodes the packets queued by the network side as the graph needs frames.
+struct PlaybackDecoder {
+    ring: RingReader,
+    decoder: StreamDecoder,
+    packet: Vec<u8>,
+    /// Decoded interleaved samples, and how many of them were played
+    samples: Vec<f32>,
+    played: usize,
+    /// Whether the server stopped sending audio, nothing is missing then
+    paused: bool,
+    /// Whether packets were taken from the ring since the network side was last woken
+    consumed: bool,
+    queued: Arc<AtomicUsize>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl PlaybackDecoder {
+    /// The next frame to play, `None` when nothing arrived in time.
+    fn next_frame(&mut self) -> Option<(f32, f32)> {
+        while self.played == self.samples.len() {
+            if !self.decode_next() {
+                return None;
+            }
+        }
+        let frame = (self.samples[self.played], self.samples[self.played + 1]);
+        self.played += CHANNELS;
+        Some(frame)
+    }
+
+    /// Decodes the next packet in the ring, returns false when there is none.
+    fn decode_next(&mut self) -> bool {
+        // The network side writes each packet with its length at once
+        let mut len = [0; 2];
+        if self.ring.available() < len.len() {
+            return false;
+        }
+        self.ring.read(&mut len);
+        self.consumed = true;
+        self.packet.resize(u16::from_le_bytes(len) as usize, 0);
+        self.ring.read(&mut self.packet);
+        let codec = self.decoder.codec();
+        self.samples.clear();
+        self.played = 0;
+        let pcm = match self.decoder.process(&self.packet) {
+            Packet::Audio(pcm) => {
+                let frames = codec.packet_frames(self.packet.len());
+                let _ = self
+                    .queued
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                        Some(queued.saturating_sub(frames))
+                    });
+                self.paused = false;
+                pcm
+            }
+            Packet::Silence(pcm) => {
+                self.paused = true;
+                pcm
+            }
+            Packet::Control(_) => return true,
+        };
+        let samples = pcm.chunks_exact(codec.bytes_per_sample()).map(from_sample);
+        self.samples.extend(samples);
+        true
+    }
+}
+
+struct PlaybackHandler {
+    left: Port<AudioOut>,
+    right: Port<AudioOut>,
+    decoder: PlaybackDecoder,
+    underruns: Arc<AtomicUsize>,
+}
+
+impl ProcessHandler for PlaybackHandler {
+    fn process(&mut self, _: &Client, scope: &ProcessScope) -> jack::Control {
+        let mut short = false;
+        let left = self.left.as_mut_slice(scope);
+        let right = self.right.as_mut_slice(scope);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            // Silence for what did not arrive in time
+            (*l, *r) = self.decoder.next_frame().unwrap_or_else(|| {
+                short = true;
+                (0.0, 0.0)
+            });
+        }
+        let decoder = &mut self.decoder;
+        let pending = (decoder.samples.len() - decoder.played) / CHANNELS;
+        decoder.pending.store(pending, Ordering::Relaxed);
+        if short && !decoder.paused {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        if std::mem::take(&mut decoder.consumed) {
+            decoder.ring.wake();
+        }
+        jack::Control::Continue
+    }
+}
+
+pub struct JackPlayback {
+    _client: jack::AsyncClient<Shutdown, PlaybackHandler>,
+    ring: RingWriter,
+    /// Length and packet, written to the ring at once
+    entry: Vec<u8>,
+    /// Codec of the packets sent, to count their frames
+    codec: Codec,
+    period: Duration,
+    /// Frames of the audio packets in the ring, and decoded but not played
+    queued: Arc<AtomicUsize>,
+    pending: Arc<AtomicUsize>,
+    underruns: Arc<AtomicUsize>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl JackPlayback {
+    /// Registers the `out_left` / `out_right` ports of the client `name` and connects them to
+    /// the first two physical outputs. The process callback decodes the packets sent, starting
+    /// with `codec`, with buffers for packets of `frames_per_packet` frames.
+    pub fn new(name: &str, codec: Codec, frames_per_packet: usize) -> io::Result<JackPlayback> {
+        let client = open_client(name)?;
+        let period = frames_duration(client.buffer_size() as usize);
+        let left = client
+            .register_port("out_left", AudioOut)
+            .map_err(jack_error)?;
+        let right = client
+            .register_port("out_right", AudioOut)
+            .map_err(jack_error)?;
+        let ports = [
+            left.name().map_err(jack_error)?,
+            right.name().map_err(jack_error)?,
+        ];
+        let entry = 2 + Codec::Pcm.packet_size(frames_per_packet).max(MAX_PACKET);
+        let (ring, reader) = ring(QUEUE_PACKETS * entry);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let pending = Arc::new(AtomicUsize::new(0));
+        let underruns = Arc::new(AtomicUsize::new(0));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let handler = PlaybackHandler {
+            left,
+            right,
+            decoder: PlaybackDecoder {
+                ring: reader,
+                decoder: StreamDecoder::with_frames(codec, frames_per_packet),
+                packet: Vec::with_capacity(MAX_PACKET),
+                // The decoder's synchronization may hand out a codeword more than the packet
+                samples: Vec::with_capacity((frames_per_packet + 8) * CHANNELS),
+                played: 0,
+                paused: false,
+                consumed: false,
+                queued: queued.clone(),
+                pending: pending.clone(),
+            },
+            underruns: underruns.clone(),
+        };
+        let client = client
+            .activate_async(Shutdown(shutdown.clone()), handler)
+            .map_err(jack_error)?;
+        let outputs = client.as_client().ports(
+            None,
+            Some(jack::jack_sys::FLOAT_MONO_AUDIO),
+            PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL,
+        );
+        for (port, output) in ports.iter().zip(&outputs) {
+            if let Err(err) = client.as_client().connect_ports_by_name(port, output) {
+                warn!("JACK: fail to connect {port} to {output}: {err}");
+            }
+        }
+        Ok(JackPlayback {
+            _client: client,
+            ring,
+            entry: Vec::with_capacity(entry),
+            codec,
+            period,
+            queued,
+            pending,
+            underruns,
+            shutdown,
+        })
+    }
+
+    /// Queues `packet`, audio or control, for the callback. Blocks while the ring is full.
+    pub fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet.len() > MAX_PACKET {
+            return Err(io::Error::other(format!(
+                "Packet of {} bytes, JACK takes up to {MAX_PACKET}",
+                packet.len()
+            )));
+        }
+        match Control::parse(packet) {
+            Some(Control::Codec(codec) | Control::Resume(codec)) => self.codec = codec,
+            Some(_) => {}
+            None => {
+                let frames = self.codec.packet_frames(packet.len());
+                self.queued.fetch_add(frames, Ordering::Relaxed);
+            }
+        }
+        self.entry.clear();
+        self.entry
+            .extend_from_slice(&(packet.len() as u16).to_le_bytes());
+        self.entry.extend_from_slice(packet);
+        while self.ring.space() < self.entry.len() {
+            check_shutdown(&self.shutdown)?;
+            self.ring.wait();
+        }
+        self.ring.write(&self.entry);
+        let underruns = self.underruns.swap(0, Ordering::Relaxed);
+        if underruns > 0 {
+            warn!("JACK: {underruns} periods short of audio");
+        }
+        Ok(())
+    }
+
+    /// Audio in the ring and decoded.
+    pub fn latency(&self) -> Duration {
+        let frames = self.queued.load(Ordering::Relaxed) + self.pending.load(Ordering::Relaxed);
+        frames_duration(frames)
+    }
+
+    /// The period in the graph.
+    pub fn playback_latency(&self) -> Duration {
+        self.period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_conversion() {
+        for value in [-1.0, -0.25, 0.0, 0.5] {
+            let s24 = to_sample(value, 3);
+            assert!((from_sample(&s24[..3]) - value).abs() < 1e-6);
+            let s16 = to_sample(value, 2);
+            assert!((from_sample(&s16[..2]) - value).abs() < 1e-4);
+        }
+        assert_eq!(to_sample(2.0, 2)[..2], 32767i16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_entry_header() {
+        for outgoing in [
+            Outgoing::Audio {
+                marker: None,
+                resumed_after: 0,
+            },
+            Outgoing::Audio {
+                marker: Some(Control::Codec(Codec::AptxHd)),
+                resumed_after: 0,
+            },
+            Outgoing::Audio {
+                marker: Some(Control::Resume(Codec::Aptx)),
+                resumed_after: 70000,
+            },
+            Outgoing::Silence { packets: 3 },
+        ] {
+            assert_eq!(
+                parse_entry_header(entry_header(outgoing, 480)),
+                (outgoing, 480)
+            );
+        }
+    }
+}
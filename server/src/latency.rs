@@ -0,0 +1,149 @@
+//! End-to-end latency of each receiver, from the capture on the server to the receiver's output.
+//! The server sends a [`Control::Probe`] in band now and then, receivers echo it back for the
+//! network round trip, and their [`Report`]s tell how much audio they keep queued.
+
+use crate::{
+    protocol::{Control, Report},
+    stream::LatencyBudget,
+};
+use std::time::{Duration, Instant};
+
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the server logs the estimate of each receiver.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Echoes slower than this are dropped, they were stuck behind a stall and would skew the average.
+const MAX_ROUND_TRIP: Duration = Duration::from_secs(2);
+/// Receivers silent for this long are forgotten.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Timestamps probes on the server and times their echoes.
+pub struct ProbeClock {
+    start: Instant,
+}
+
+impl ProbeClock {
+    pub fn new() -> ProbeClock {
+        ProbeClock {
+            start: Instant::now(),
+        }
+    }
+
+    pub fn probe(&self, id: u32, now: Instant) -> Control {
+        Control::Probe {
+            id,
+            sent_us: now.duration_since(self.start).as_micros() as u64,
+        }
+    }
+
+    /// Round trip of the probe echoed with `sent_us`, `None` when it makes no sense.
+    pub fn round_trip(&self, sent_us: u64, now: Instant) -> Option<Duration> {
+        let sent = self.start.checked_add(Duration::from_micros(sent_us))?;
+        let round_trip = now.checked_duration_since(sent)?;
+        (round_trip <= MAX_ROUND_TRIP).then_some(round_trip)
+    }
+}
+
+impl Default for ProbeClock {
+    fn default() -> Self {
+        ProbeClock::new()
+    }
+}
+
+/// What the server knows about the latency of one receiver.
+#[derive(Debug, Default, Clone)]
+pub struct ClientLatency {
+    /// Smoothed like TCP does, a late echo only moves it by an eighth
+    round_trip: Option<Duration>,
+    buffer: Duration,
+    playback: Duration,
+    updated: Option<Instant>,
+}
+
+impl ClientLatency {
+    pub fn new() -> ClientLatency {
+        ClientLatency::default()
+    }
+
+    pub fn echo(&mut self, round_trip: Duration, now: Instant) {
+        self.round_trip = Some(match self.round_trip {
+            Some(smoothed) if round_trip > smoothed => smoothed + (round_trip - smoothed) / 8,
+            Some(smoothed) => smoothed - (smoothed - round_trip) / 8,
+            None => round_trip,
+        });
+        self.updated = Some(now);
+    }
+
+    pub fn report(&mut self, report: &Report, now: Instant) {
+        self.buffer = Duration::from_millis(report.buffer_ms.into());
+        self.playback = Duration::from_millis(report.playback_ms.into());
+        self.updated = Some(now);
+    }
+
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip
+    }
+
+    pub fn is_stale(&self, now: Instant) -> bool {
+        self.updated
+            .is_none_or(|updated| now.duration_since(updated) > STALE_AFTER)
+    }
+
+    /// `budget` of the server's side of the stream, completed with what the receiver measured.
+    pub fn estimate(&self, budget: LatencyBudget) -> LatencyBudget {
+        LatencyBudget {
+            network: self.round_trip.unwrap_or_default() / 2,
+            jitter_buffer: self.buffer,
+            playback: self.playback,
+            ..budget
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Codec;
+
+    #[test]
+    fn test_round_trip() {
+        let clock = ProbeClock::new();
+        let now = Instant::now();
+        let Control::Probe { sent_us, .. } = clock.probe(1, now) else {
+            panic!("Not a probe");
+        };
+        let echoed = now + Duration::from_millis(8);
+        // Timestamps are truncated to the microsecond
+        let round_trip = clock.round_trip(sent_us, echoed).unwrap();
+        assert!(round_trip - Duration::from_millis(8) < Duration::from_micros(1));
+        assert_eq!(
+            clock.round_trip(sent_us, now + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(clock.round_trip(u64::MAX / 2, now), None);
+    }
+
+    #[test]
+    fn test_client_estimate() {
+        let now = Instant::now();
+        let mut latency = ClientLatency::new();
+        assert!(latency.is_stale(now));
+        latency.echo(Duration::from_millis(8), now);
+        latency.echo(Duration::from_millis(16), now);
+        assert_eq!(latency.round_trip(), Some(Duration::from_millis(9)));
+        latency.report(
+            &Report {
+                received: 94,
+                lost: 0,
+                jitter_us: 500,
+                buffer_ms: 20,
+                playback_ms: 12,
+            },
+            now,
+        );
+        assert!(!latency.is_stale(now + Duration::from_secs(1)));
+        let budget = LatencyBudget::new(Codec::Pcm, 480, 960, Duration::ZERO);
+        let estimate = latency.estimate(budget);
+        assert_eq!(estimate.network, Duration::from_micros(4500));
+        assert_eq!(estimate.total(), Duration::from_micros(66500));
+    }
+}
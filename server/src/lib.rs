@@ -1,27 +1,79 @@
-#![feature(once_cell_get_mut)]
-#[allow(dead_code)]
-mod aptx;
+//! aptX and aptX HD codec, a Rust port of libopenaptx.
+//!
+//! ```
+//! use aptx_rust::aptx::AptxContext;
+//!
+//! let mut encoder = AptxContext::new(false);
+//! let mut decoder = AptxContext::new(false);
+//! let pcm = vec![0u8; 2048];
+//! let mut encoded = vec![0u8; 512];
+//! let mut decoded = vec![0u8; 2048];
+//! let mut written = 0;
+//!
+//! encoder.encode(&pcm, &mut encoded, &mut written);
+//! decoder.decode(&encoded[..written], &mut decoded, &mut written);
+//! ```
+//!
+//! Without the default `std` feature the crate is `no_std`, `alloc` brings back
+//! [`aptx::AptxContext::new`]. The `capi` feature exports the libopenaptx C interface.
+//!
+//! The default `relay` feature adds the relay's wire format and the pieces both of its ends are
+//! built from: [`protocol`], [`stream`], [`adapt`], [`buffering`] and [`latency`]. They are
+//! public API like [`aptx`] and follow the same semver rules, receivers written against them
+//! keep talking to `server_pulse` within a major version.
+#![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "relay")]
+pub mod adapt;
+pub mod aptx;
+#[cfg(feature = "relay")]
+pub mod buffering;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "relay")]
+pub mod latency;
+#[cfg(feature = "relay")]
+pub mod protocol;
+#[cfg(feature = "relay")]
+pub mod stream;
 
-#[cfg(target_os = "android")]
+#[cfg(all(target_os = "android", feature = "relay"))]
 #[allow(non_snake_case)]
 pub mod android {
     extern crate android_logger;
     extern crate log;
-    use crate::aptx::AptxContext;
+    use crate::{
+        adapt::Monitor,
+        protocol::{Codec, Control, MIC_CODEC},
+        stream::{s16_mono_to_s24_stereo, s24_to_s16, Packet, StreamDecoder, StreamEncoder},
+    };
     use android_logger::{Config, FilterBuilder};
     use jni::{
         objects::{JByteArray, JClass},
+        sys::jint,
         JNIEnv,
     };
-    use log::error;
     use log::LevelFilter;
-    use std::sync::OnceLock;
+    use log::{error, info};
+    use std::{
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            Mutex,
+        },
+        time::Instant,
+    };
 
-    static mut APTX_CONTEXT: OnceLock<Box<AptxContext>> = OnceLock::new();
-    static mut DECODED_BUFFER: OnceLock<Vec<u8>> = OnceLock::new();
+    static DECODER: Mutex<Option<StreamDecoder>> = Mutex::new(None);
+    static MONITOR: Mutex<Option<Monitor>> = Mutex::new(None);
+    /// Last volume pushed by the server, 0 when muted, -1 until it is known
+    static VOLUME: AtomicI32 = AtomicI32::new(-1);
+    /// AudioTrack is opened for 16 bit, aptX HD output is reduced to it here
+    static DECODED_BUFFER: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+    static MIC_ENCODER: Mutex<Option<StreamEncoder>> = Mutex::new(None);
+    /// Last latency probe from the server, echoed with the next report
+    static PROBE: Mutex<Option<Control>> = Mutex::new(None);
 
     #[no_mangle]
-    pub unsafe extern "C" fn Java_com_example_pcstream_AudioService_init_1decode_1rust(
+    pub extern "C" fn Java_com_example_pcstream_AudioService_init_1decode_1rust(
         _env: JNIEnv,
         _: JClass,
     ) -> i32 {
@@ -36,41 +88,176 @@ pub mod android {
                         .build(),
                 ),
         );
-        let _ctx = APTX_CONTEXT.get_or_init(|| AptxContext::new(false));
-        DECODED_BUFFER.get_or_init(|| vec![0; 2048]);
+        DECODER
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| StreamDecoder::new(Codec::Aptx));
+        MONITOR.lock().unwrap().get_or_insert_with(Monitor::new);
         1
     }
 
+    /// Decodes one packet from the audio port into 16 bit PCM, following the codec changes
+    /// announced by the server. Returns the number of bytes written to `output`.
     #[no_mangle]
-    pub unsafe extern "C" fn Java_com_example_pcstream_AudioService_decode_1rust(
-        mut env: JNIEnv,
+    pub extern "C" fn Java_com_example_pcstream_AudioService_decode_1rust(
+        env: JNIEnv,
         _: JClass,
         input: JByteArray,
+        length: jint,
         output: JByteArray,
-    ) {
-        let ctx = APTX_CONTEXT.get_mut().expect("Fail to get context");
-        let data: Vec<u8> = env
-            .get_array_elements(&input, jni::objects::ReleaseMode::NoCopyBack)
-            .expect("Fail to get elements")
-            .iter()
-            .map(|&x| x as u8)
-            .collect();
-        let mut written = 0;
-        let mut dropped = 0;
-        let mut synced = false;
-        let processed = ctx.decode_sync(
-            &data,
-            DECODED_BUFFER.get_mut().expect("Fail to get context"),
-            &mut written,
-            &mut synced,
-            &mut dropped,
-        );
-        if processed != data.len() {
-            error!("Fail to process audio {} {}", processed, data.len());
+    ) -> jint {
+        let mut decoder = DECODER.lock().unwrap();
+        let decoder = decoder.as_mut().expect("Fail to get context");
+        let mut data = env
+            .convert_byte_array(&input)
+            .expect("Fail to get elements");
+        data.truncate(length.max(0) as usize);
+        let hd = decoder.codec().hd();
+        let mut out_buffer = DECODED_BUFFER.lock().unwrap();
+        let mut monitor = MONITOR.lock().unwrap();
+        let monitor = monitor.as_mut().expect("Fail to get monitor");
+        let (pcm, audio) = match decoder.process(&data) {
+            Packet::Audio(pcm) => (pcm, true),
+            Packet::Silence(pcm) => (pcm, false),
+            Packet::Control(Control::Volume(volume)) => {
+                let percent = if volume.muted { 0 } else { volume.percent };
+                VOLUME.store(percent.into(), Ordering::Relaxed);
+                return 0;
+            }
+            Packet::Control(probe @ Control::Probe { .. }) => {
+                *PROBE.lock().unwrap() = Some(probe);
+                return 0;
+            }
+            Packet::Control(control) => {
+                info!("{control:?}");
+                return 0;
+            }
+        };
+        if hd {
+            s24_to_s16(pcm, &mut out_buffer);
+        } else {
+            out_buffer.clear();
+            out_buffer.extend_from_slice(pcm);
+        }
+        if audio {
+            monitor.packet(Instant::now(), out_buffer.len() / 4);
+        } else {
+            monitor.silence();
+        }
+        let capacity = env.get_array_length(&output).unwrap_or(0).max(0) as usize;
+        if out_buffer.len() > capacity {
+            error!(
+                "Output buffer too small {} < {}",
+                capacity,
+                out_buffer.len()
+            );
+            out_buffer.truncate(capacity);
+        }
+        let out_buffer: Vec<i8> = out_buffer.iter().map(|&x| x as i8).collect();
+        env.set_byte_array_region(output, 0, &out_buffer)
+            .expect("Fail to set output buffer");
+        out_buffer.len() as jint
+    }
+
+    /// Codec the stream is currently decoded with, see `protocol::Codec::id`.
+    #[no_mangle]
+    pub extern "C" fn Java_com_example_pcstream_AudioService_codec_1rust(
+        _env: JNIEnv,
+        _: JClass,
+    ) -> jint {
+        DECODER
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(Codec::Aptx, |decoder| decoder.codec())
+            .id() as jint
+    }
+
+    /// Volume of the stream set on the server in percent, 0 when muted, -1 until known.
+    #[no_mangle]
+    pub extern "C" fn Java_com_example_pcstream_AudioService_volume_1rust(
+        _env: JNIEnv,
+        _: JClass,
+    ) -> jint {
+        VOLUME.load(Ordering::Relaxed)
+    }
+
+    /// Fills `output` with the packet due for the server's address port, if any, and returns
+    /// its length: the echo of a latency probe, or the feedback report. `buffer_ms` is the audio
+    /// queued in the AudioTrack, `playback_ms` the delay of the output after it.
+    #[no_mangle]
+    pub extern "C" fn Java_com_example_pcstream_AudioService_report_1rust(
+        env: JNIEnv,
+        _: JClass,
+        buffer_ms: jint,
+        playback_ms: jint,
+        output: JByteArray,
+    ) -> jint {
+        let control = PROBE.lock().unwrap().take().or_else(|| {
+            let mut monitor = MONITOR.lock().unwrap();
+            let report = monitor.as_mut()?.report(
+                Instant::now(),
+                buffer_ms.max(0) as u32,
+                playback_ms.max(0) as u32,
+            )?;
+            Some(Control::Report(report))
+        });
+        let Some(control) = control else {
+            return 0;
+        };
+        let report: Vec<i8> = control.to_bytes().iter().map(|&x| x as i8).collect();
+        env.set_byte_array_region(output, 0, &report)
+            .expect("Fail to set output buffer");
+        report.len() as jint
+    }
+
+    /// Starts a new microphone stream. Fills `output` with the resume marker to send first, which
+    /// restarts the server's decoder, and returns its length.
+    #[no_mangle]
+    pub extern "C" fn Java_com_example_pcstream_AudioService_init_1encode_1rust(
+        env: JNIEnv,
+        _: JClass,
+        output: JByteArray,
+    ) -> jint {
+        let mut encoder = StreamEncoder::new(MIC_CODEC);
+        let marker = encoder.resume().to_bytes();
+        *MIC_ENCODER.lock().unwrap() = Some(encoder);
+        let marker: Vec<i8> = marker.iter().map(|&x| x as i8).collect();
+        env.set_byte_array_region(output, 0, &marker)
+            .expect("Fail to set output buffer");
+        marker.len() as jint
+    }
+
+    /// Encodes 16 bit mono microphone samples into a packet for the server's microphone port.
+    /// Returns the number of bytes written to `output`.
+    #[no_mangle]
+    pub extern "C" fn Java_com_example_pcstream_AudioService_encode_1rust(
+        env: JNIEnv,
+        _: JClass,
+        input: JByteArray,
+        length: jint,
+        output: JByteArray,
+    ) -> jint {
+        let mut encoder = MIC_ENCODER.lock().unwrap();
+        let encoder = encoder.get_or_insert_with(|| StreamEncoder::new(MIC_CODEC));
+        let mut data = env
+            .convert_byte_array(&input)
+            .expect("Fail to get elements");
+        data.truncate(length.max(0) as usize);
+        // The codec takes groups of 4 frames
+        data.truncate(data.len() / 8 * 8);
+        let mut stereo = Vec::with_capacity(data.len() * 3);
+        s16_mono_to_s24_stereo(&data, &mut stereo);
+        let mut packet = Vec::new();
+        encoder.encode(&stereo, &mut packet);
+        let capacity = env.get_array_length(&output).unwrap_or(0).max(0) as usize;
+        if packet.len() > capacity {
+            error!("Output buffer too small {} < {}", capacity, packet.len());
+            return 0;
         }
-        let out_buffer: &Vec<i8> =
-            std::mem::transmute(DECODED_BUFFER.get().expect("Fail to get context"));
-        env.set_byte_array_region(output, 0, out_buffer)
+        let packet: Vec<i8> = packet.iter().map(|&x| x as i8).collect();
+        env.set_byte_array_region(output, 0, &packet)
             .expect("Fail to set output buffer");
+        packet.len() as jint
     }
 }
@@ -1,27 +1,128 @@
+#[cfg(all(not(target_os = "android"), feature = "alsa"))]
 #[allow(dead_code)]
-mod aptx;
+mod alsa_pcm;
+#[cfg(all(not(target_os = "android"), feature = "jack"))]
+#[allow(dead_code)]
+mod jack_client;
+#[cfg(all(not(target_os = "android"), feature = "pipewire"))]
+#[allow(dead_code)]
+mod pipewire_stream;
+#[cfg(all(
+    not(target_os = "android"),
+    any(feature = "pipewire", feature = "jack")
+))]
+#[allow(dead_code)]
+mod ring;
+#[cfg(not(target_os = "android"))]
+mod sink;
+#[cfg(not(target_os = "android"))]
+#[allow(dead_code)]
+mod utils;
+
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Print the PulseAudio sinks to pick from with --sink pulse:<name>
+    ListSinks,
+}
+
+#[cfg(not(target_os = "android"))]
+use aptx_rust::buffering::{Profile, PulseOverrides};
 
 #[cfg(not(target_os = "android"))]
 #[derive(Debug, clap::Parser)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     with_aptx: bool,
 
     #[arg(long)]
     hd: bool,
+
+    /// Where to play: pulse[:sink], pipewire[:node], alsa[:device] or jack[:client]
+    #[arg(long, default_value = "pulse")]
+    sink: sink::SinkSpec,
+
+    /// Buffering of both ends: ultra-low, balanced or robust, the server must use the same
+    #[arg(long, default_value = "balanced")]
+    profile: Profile,
+
+    /// Frames per packet sent by the server, defaults to the profile
+    #[arg(long)]
+    packet_frames: Option<usize>,
+
+    /// Playback buffer length in packets, defaults to the profile
+    #[arg(long, alias = "alsa-periods")]
+    buffer_packets: Option<usize>,
+
+    /// Playback buffer length in ms, rounded up to whole packets. Overrides --buffer-packets
+    #[arg(long)]
+    latency_ms: Option<u64>,
+
+    /// PulseAudio buffer attributes in bytes, as maxlength=..,tlength=..,prebuf=..,minreq=..
+    #[arg(long, default_value = "")]
+    pulse_buffer: PulseOverrides,
+}
+
+#[cfg(not(target_os = "android"))]
+fn list_sinks() {
+    let (sinks, default_sink) =
+        utils::pulse_list_sinks().unwrap_or_else(|err| utils::exit_with_error(err));
+    for sink in sinks {
+        let default = default_sink.as_deref() == Some(sink.name.as_str());
+        println!(
+            "{} {}\t{}",
+            if default { "*" } else { " " },
+            sink.name,
+            sink.description
+        );
+    }
+}
+
+/// Closes `output` and opens the sink again with `open`, in that order since devices like ALSA
+/// `hw:` are exclusive and the new one would fail while the old one is held.
+#[cfg(not(target_os = "android"))]
+fn reopen(
+    output: Box<dyn sink::PacketSink>,
+    open: impl FnMut() -> Result<Box<dyn sink::PacketSink>, String>,
+) -> Box<dyn sink::PacketSink> {
+    drop(output);
+    utils::retry("Reopening the output", open)
 }
 
 #[cfg(not(target_os = "android"))]
 fn main() {
-    use aptx::AptxContext;
+    use aptx_rust::{
+        adapt::Monitor,
+        protocol::{Codec, Control},
+        stream::LatencyBudget,
+    };
     use clap::Parser;
-    use libpulse_binding::{def::BufferAttr, sample, stream::Direction};
-    use libpulse_simple_binding::Simple;
-    use log::{error, info};
-    use std::net::UdpSocket;
+    use log::{debug, error, info};
+    use std::{
+        net::UdpSocket,
+        time::{Duration, Instant},
+    };
 
     env_logger::init();
     let args = Args::parse();
+    if let Some(Command::ListSinks) = args.command {
+        list_sinks();
+        return;
+    }
+    let mut buffering = args.profile.buffering();
+    buffering.packet_frames = args.packet_frames.unwrap_or(buffering.packet_frames);
+    buffering.playback_packets = args.buffer_packets.unwrap_or(buffering.playback_packets);
+    if let Some(latency) = args.latency_ms {
+        buffering.playback_packets = buffering.packets_in(Duration::from_millis(latency));
+    }
+    if let Err(err) = buffering.validate() {
+        error!("{err}");
+        return;
+    }
+    info!("Profile {}: {buffering:?}", args.profile);
     args.hd.then(|| info!("HD enabled"));
     args.with_aptx.then(|| info!("APTX enabled"));
 
@@ -31,63 +132,78 @@ fn main() {
         .expect("Fail to connect to server");
     let sock_audio = UdpSocket::bind("127.0.0.1:4051").unwrap();
 
-    let mut aptx_ctx = AptxContext::new(args.hd);
-    let format = if args.hd {
-        sample::Format::S24le
-    } else {
-        sample::Format::S16le
-    };
-    let audio_spec = sample::Spec {
-        format,
-        rate: 48000,
-        channels: 2,
+    // Only a starting point, the server announces the codec in band
+    let codec = match (args.with_aptx, args.hd) {
+        (false, _) => Codec::Pcm,
+        (true, false) => Codec::Aptx,
+        (true, true) => Codec::AptxHd,
     };
-    let attr = BufferAttr {
-        maxlength: 65536,
-        tlength: 2048,
-        prebuf: 512,
-        minreq: 512,
-        fragsize: 2048,
-    };
-    let pulse_cnn = Simple::new(
-        None,
-        "pc_relay",
-        Direction::Playback,
-        None,
-        "System sound",
-        &audio_spec,
-        None,
-        Some(&attr),
-    )
-    .expect("Fail to connect to the audio server");
-    let mut buffer = [0; 512];
-    let mut out_buffer = [0; 2048];
-    let mut written = 0;
-    let mut dropped = 0;
-    let mut synced = false;
+    let open = |codec| args.sink.open(codec, &buffering, &args.pulse_buffer);
+    // The sink going away later is waited out, a wrong one is an error
+    let mut output = open(codec).unwrap_or_else(|err| utils::exit_with_error(err));
+    // Followed from the control packets, the output decodes them on its own
+    let mut playing = codec;
+    let mut silent = false;
+    let mut monitor = Monitor::new();
+    let mut budget_due = true;
+    let mut buffer = [0; 4096];
     loop {
-        let _ = sock_audio.recv_from(&mut buffer);
-        if args.with_aptx {
-            let processed = aptx_ctx.decode_sync(
-                &buffer,
-                &mut out_buffer,
-                &mut written,
-                &mut synced,
-                &mut dropped,
-            );
-            match pulse_cnn.write(&out_buffer) {
-                Ok(_) => {}
-                Err(_) => error!("Fail to write audio"),
+        let nbytes = match sock_audio.recv_from(&mut buffer) {
+            Ok((nbytes, _)) => nbytes,
+            Err(err) => {
+                error!("{err}");
+                continue;
+            }
+        };
+        let packet = &buffer[..nbytes];
+        let control = Control::parse(packet);
+        match control {
+            Some(Control::Silence { .. }) if !silent => {
+                silent = true;
+                monitor.silence();
+                debug!("Server is silent");
             }
-            if !synced || dropped > 0 {
-                error!("aptX decoding failed, synchronizing {written} {synced} {dropped}");
+            Some(Control::Codec(codec) | Control::Resume(codec)) => {
+                if codec != playing {
+                    info!("Codec changed {playing} -> {codec}");
+                    budget_due = true;
+                }
+                playing = codec;
             }
-            if processed != buffer.len() {
-                error!("aptX decoding failed {written} != {}", out_buffer.len());
-                break;
+            Some(Control::Volume(volume)) => debug!("Volume {volume}"),
+            Some(probe @ Control::Probe { .. }) => {
+                let _ = sock_addr.send_to(&probe.to_bytes(), "127.0.0.1:4052");
             }
-        } else {
-            let _ = pulse_cnn.write(&buffer);
+            _ => {}
+        }
+        if let Err(err) = output.send(packet) {
+            error!("Fail to play: {err}");
+            output = reopen(output, || open(playing));
+        }
+        if control.is_some() {
+            continue;
+        }
+        silent = false;
+        let now = Instant::now();
+        let frames = playing.packet_frames(nbytes);
+        monitor.packet(now, frames);
+        let (jitter_buffer, playback) = (output.latency(), output.playback_latency());
+        if budget_due {
+            // The server captures one packet at a time
+            let budget = LatencyBudget {
+                playback,
+                ..LatencyBudget::new(playing, frames, frames, jitter_buffer)
+            };
+            info!("Latency budget: {budget}");
+            budget_due = false;
+        }
+        if let Some(report) = monitor.report(
+            now,
+            jitter_buffer.as_millis() as u32,
+            playback.as_millis() as u32,
+        ) {
+            debug!("{report:?}");
+            let _ = sock_addr.send_to(&Control::Report(report).to_bytes(), "127.0.0.1:4052");
         }
     }
 }
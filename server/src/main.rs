@@ -1,11 +1,59 @@
+#[cfg(all(not(target_os = "android"), feature = "alsa"))]
 #[allow(dead_code)]
-mod aptx;
+mod alsa_pcm;
+#[cfg(all(not(target_os = "android"), feature = "jack"))]
+#[allow(dead_code)]
+mod jack_client;
+#[cfg(not(target_os = "android"))]
+mod mic;
+#[cfg(all(not(target_os = "android"), feature = "pipewire"))]
+#[allow(dead_code)]
+mod pipewire_stream;
+#[cfg(all(
+    not(target_os = "android"),
+    any(feature = "pipewire", feature = "jack")
+))]
+#[allow(dead_code)]
+mod ring;
+#[cfg(not(target_os = "android"))]
+#[allow(dead_code)]
+mod sink;
+#[cfg(not(target_os = "android"))]
+mod source;
 #[cfg(not(target_os = "android"))]
 mod utils;
+#[cfg(not(target_os = "android"))]
+#[allow(dead_code)]
+mod wav;
+
+/// Resend the current codec about once per second
+#[cfg(not(target_os = "android"))]
+const CODEC_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Quiet audio still sent before going silent, flushes the codec's delay line
+#[cfg(not(target_os = "android"))]
+const SILENCE_HANGOVER: Duration = Duration::from_millis(320);
+/// Keep-alive about every half second while silent
+#[cfg(not(target_os = "android"))]
+const SILENCE_KEEPALIVE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[cfg(not(target_os = "android"))]
+use aptx_rust::buffering::{Profile, PulseOverrides};
+#[cfg(not(target_os = "android"))]
+use std::time::Duration;
+
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Print the PulseAudio sources to pick from with --source pulse:<name>
+    ListSources,
+}
 
 #[cfg(not(target_os = "android"))]
 #[derive(Debug, clap::Parser)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     with_aptx: bool,
 
@@ -23,112 +71,365 @@ struct Args {
 
     #[arg(long, default_value_t = 4053)]
     port_cmds: u16,
+
+    /// Play the phone's microphone into a virtual source other apps can record from
+    #[arg(long)]
+    mic: bool,
+
+    #[arg(long, default_value_t = 4054)]
+    port_mic: u16,
+
+    /// Stop streaming while the captured audio is silent, only sending sparse keep-alives
+    #[arg(long)]
+    dtx: bool,
+
+    /// Pick the codec from the receiver's reports, from PCM down to half rate aptX as conditions
+    /// worsen
+    #[arg(long)]
+    adaptive: bool,
+
+    /// Audio to stream: pulse[:source], phone, app:name|pid, pipewire[:node], alsa[:device], jack[:client],
+    /// file:path[,path...], silence, sine[:freq], sweep[:secs], pink or click[:ms]
+    #[arg(long, default_value = "pulse")]
+    source: source::SourceSpec,
+
+    /// Buffering of both ends: ultra-low, balanced or robust, receivers must use the same
+    #[arg(long, default_value = "balanced")]
+    profile: Profile,
+
+    /// Frames per packet, defaults to the profile
+    #[arg(long)]
+    packet_frames: Option<usize>,
+
+    /// Capture buffer length in packets, defaults to the profile
+    #[arg(long, alias = "alsa-periods")]
+    buffer_packets: Option<usize>,
+
+    /// PulseAudio buffer attributes in bytes, as maxlength=..,fragsize=..
+    #[arg(long, default_value = "")]
+    pulse_buffer: PulseOverrides,
+
+    /// Keep the volume to the stream rather than following the volume of the captured sink
+    #[arg(long)]
+    independent_volume: bool,
+
+    /// Level below which captured audio is considered silent
+    #[arg(long, default_value_t = -80.0, allow_negative_numbers = true)]
+    silence_db: f64,
+}
+
+#[cfg(not(target_os = "android"))]
+fn list_sources() {
+    let (sources, default_sink) =
+        utils::pulse_list_sources().unwrap_or_else(|err| utils::exit_with_error(err));
+    for source in sources {
+        // The one `--source pulse` picks
+        let default = source.monitor_of.is_some() && source.monitor_of == default_sink;
+        println!(
+            "{} {}\t{}",
+            if default { "*" } else { " " },
+            source.name,
+            source.description
+        );
+    }
 }
 
 #[cfg(not(target_os = "android"))]
 fn main() {
-    use crate::aptx::AptxContext;
+    use aptx_rust::{
+        adapt::Adaptation,
+        latency::{ClientLatency, ProbeClock, LOG_INTERVAL, PROBE_INTERVAL},
+        protocol::{Codec, Control, VolumeCommand, CONTROL_MAX_LEN},
+        stream::{LatencyBudget, Outgoing, SilenceDetector, StreamSettings, Transmitter},
+    };
     use clap::Parser;
     use log::{debug, error, info};
     use std::{
+        collections::HashMap,
         net::{Ipv4Addr, SocketAddr, UdpSocket},
-        sync::{Arc, Mutex},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Instant,
     };
 
     std::env::set_var("RUST_LOG", "info");
     env_logger::init();
     let args = Args::parse();
+    if let Some(Command::ListSources) = args.command {
+        list_sources();
+        return;
+    }
+    let mut buffering = args.profile.buffering();
+    buffering.packet_frames = args.packet_frames.unwrap_or(buffering.packet_frames);
+    buffering.capture_packets = args.buffer_packets.unwrap_or(buffering.capture_packets);
+    if let Err(err) = buffering.validate() {
+        error!("{err}");
+        return;
+    }
+    info!("Profile {}: {buffering:?}", args.profile);
+    let frames_per_packet = buffering.packet_frames;
+    let codec_announce_packets = buffering.packets_in(CODEC_ANNOUNCE_INTERVAL);
+    let silence_keepalive_packets = buffering.packets_in(SILENCE_KEEPALIVE_INTERVAL);
+    utils::handle_exit_signals();
     args.with_aptx.then(|| info!("APTX enabled"));
     args.hd.then(|| info!("HD enabled"));
     let client_addr = Arc::new(Mutex::new(SocketAddr::new(
         Ipv4Addr::new(192, 168, 0, 13).into(),
         args.port_audio,
     )));
+    // Read for every packet by the capture, which may encode in an audio callback
+    let settings = Arc::new(StreamSettings::new(match (args.with_aptx, args.hd) {
+        (false, _) => Codec::Pcm,
+        (true, false) => Codec::Aptx,
+        (true, true) => Codec::AptxHd,
+    }));
+    // Mixer changes on the PC reach the receiver, and its volume keys move the sink. The
+    // monitor of the sink already carries its volume, the receiver only shows it.
+    let follow_sink = !args.independent_volume && args.source.monitored_sink().is_some();
+    let sink_volume = follow_sink.then(|| {
+        let (source, settings) = (args.source.clone(), settings.clone());
+        utils::pulse_watch_sink_volume(
+            move || {
+                source
+                    .monitored_sink()
+                    .unwrap_or(Err("Not a sink".to_string()))
+            },
+            move |sink_volume| settings.set_volume(sink_volume),
+        )
+        .unwrap_or_else(|err| utils::exit_with_error(err))
+    });
+    let change_volume = |command: VolumeCommand| {
+        let volume = settings.update_volume(|volume| volume.apply(command));
+        if let Some(Err(err)) = sink_volume.as_ref().map(|control| control.set(volume)) {
+            error!("Fail to set the sink volume: {err}");
+        }
+    };
+    let adaptations = Mutex::new(HashMap::new());
+    // Playback buffer of the client, from its last report
+    let client_buffer_ms = AtomicU32::new(0);
+    let clock = ProbeClock::new();
+    let latencies = Mutex::new(HashMap::<_, ClientLatency>::new());
     std::thread::scope(|s| {
+        if args.mic {
+            s.spawn(|| mic::relay_mic(&args.addr, args.port_mic, &buffering));
+        }
         s.spawn(|| {
-            utils::udp_server_loop_data::<12>(&args.addr, args.port_addr, |_, mut client| {
-                client.set_port(args.port_audio);
-                *client_addr.lock().unwrap() = client;
-                info!("New client {client_addr:?}");
-            })
+            utils::udp_server_loop_bytes::<CONTROL_MAX_LEN>(
+                &args.addr,
+                args.port_addr,
+                |data, mut client| {
+                    let report = match Control::parse(data) {
+                        Some(Control::Report(report)) => report,
+                        Some(Control::Probe { id, sent_us }) => {
+                            let now = Instant::now();
+                            if let Some(round_trip) = clock.round_trip(sent_us, now) {
+                                debug!("Probe {id} from {client}: {round_trip:?}");
+                                let mut latencies = latencies.lock().unwrap();
+                                latencies
+                                    .entry(client.ip())
+                                    .or_default()
+                                    .echo(round_trip, now);
+                            }
+                            return;
+                        }
+                        _ => {
+                            client.set_port(args.port_audio);
+                            *client_addr.lock().unwrap() = client;
+                            info!("New client {client_addr:?}");
+                            return;
+                        }
+                    };
+                    debug!("Report from {client}: {report:?}");
+                    let mut latencies = latencies.lock().unwrap();
+                    latencies
+                        .entry(client.ip())
+                        .or_default()
+                        .report(&report, Instant::now());
+                    client_buffer_ms.store(report.buffer_ms, Ordering::Relaxed);
+                    // Only the client being streamed to decides the codec
+                    if !args.adaptive || client.ip() != client_addr.lock().unwrap().ip() {
+                        return;
+                    }
+                    let mut adaptations = adaptations.lock().unwrap();
+                    let adaptation = adaptations
+                        .entry(client.ip())
+                        .or_insert_with(Adaptation::new);
+                    if let Some(next) = adaptation.update(settings.codec(), &report) {
+                        settings.set_codec(next);
+                    }
+                },
+            )
         });
         s.spawn(|| {
-            utils::udp_server_loop_data::<12>(&args.addr, args.port_cmds, |data, _| {
-                if data.trim().contains("NEXT") {
+            utils::udp_server_loop_data::<32>(&args.addr, args.port_cmds, |data, _| {
+                let data = data.trim().trim_matches('\0');
+                if data.contains("NEXT") {
                     debug!("PlayerControl::Next");
                     utils::dbus_media_control(utils::PlayerControl::Next);
-                } else if data.trim().contains("PREV") {
+                } else if data.contains("PREV") {
                     debug!("PlayerControl::Previous");
                     utils::dbus_media_control(utils::PlayerControl::Previous);
+                } else if let Some(command) = data.strip_prefix("VOLUME") {
+                    match command.parse::<VolumeCommand>() {
+                        Ok(command) => change_volume(command),
+                        Err(err) => error!("{err}"),
+                    }
+                } else if let Ok(command @ (VolumeCommand::Mute | VolumeCommand::Unmute)) =
+                    data.parse()
+                {
+                    change_volume(command);
+                } else if let Some(name) = data.strip_prefix("CODEC") {
+                    match name.parse::<Codec>() {
+                        Ok(codec) => settings.set_codec(codec),
+                        Err(err) => error!("{err}"),
+                    }
                 }
             });
         });
         s.spawn(|| {
             let socket = UdpSocket::bind("0.0.0.0:0").expect("Error creating client");
-            let format = if args.hd {
-                libpulse_binding::sample::Format::S24le
-            } else {
-                libpulse_binding::sample::Format::S16le
-            };
-            let audio_spec = libpulse_binding::sample::Spec {
-                format,
-                rate: 48000,
-                channels: 2,
+            // Starting with the codec the receiver decodes, the transmitter announces a switch
+            let open = |codec| {
+                let transmitter = Transmitter::new(
+                    codec,
+                    frames_per_packet,
+                    args.dtx.then(|| {
+                        SilenceDetector::new(
+                            args.silence_db,
+                            buffering.packets_in(SILENCE_HANGOVER),
+                        )
+                    }),
+                    // The monitor of the sink already carries its volume
+                    !follow_sink,
+                );
+                args.source.open(
+                    &buffering,
+                    &args.pulse_buffer,
+                    transmitter,
+                    settings.clone(),
+                )
             };
-            let attr = libpulse_binding::def::BufferAttr {
-                maxlength: 65536,
-                tlength: 2048,
-                prebuf: 512,
-                minreq: 512,
-                fragsize: 2048,
+            let mut source =
+                open(settings.codec()).unwrap_or_else(|err| utils::exit_with_error(err));
+            // Sources hand over one packet at a time
+            let budget = |codec| {
+                let jitter_buffer =
+                    Duration::from_millis(client_buffer_ms.load(Ordering::Relaxed).into());
+                LatencyBudget::new(codec, frames_per_packet, frames_per_packet, jitter_buffer)
             };
-            let monitor_name = utils::pulse_get_source_by_name("Monitor of Jabra");
-            info!("Output: {monitor_name}");
-            let pulse_cnn = libpulse_simple_binding::Simple::new(
-                None,
-                "pc_relay",
-                libpulse_binding::stream::Direction::Record,
-                Some(&monitor_name),
-                "System sound",
-                &audio_spec,
-                None,
-                Some(&attr),
-            )
-            .expect("Fail to connect to the audio server");
-            let mut ctx = AptxContext::new(args.hd);
-            let mut buffer = [0u8; 2048];
-            let mut enc_buffer = [0u8; 512];
-            loop {
-                match pulse_cnn.read(&mut buffer) {
-                    Ok(_) => {}
-                    Err(err) => error!("{}", err),
+            // The codec of the packets sent, switched by the transmitter
+            let mut codec = settings.codec();
+            info!("Latency budget: {}", budget(codec));
+            let mut sent_volume = None;
+            let mut packet = Vec::with_capacity(frames_per_packet * 4);
+            let (mut last_probe, mut last_log) = (Instant::now(), Instant::now());
+            let mut probe_id = 0;
+            let mut backoff = utils::Backoff::new();
+            for count in 0usize.. {
+                let outgoing = match source.next(&mut packet) {
+                    Ok(outgoing) => {
+                        backoff.reset();
+                        outgoing
+                    }
+                    Err(err) => {
+                        // Nothing to stream, and the device needs time to come back before it
+                        // is opened again
+                        let delay = backoff.next_delay();
+                        error!("{err}, reopening the source in {delay:?}");
+                        drop(source);
+                        std::thread::sleep(delay);
+                        source = utils::retry("Reopening the source", || open(codec));
+                        // The receiver's decoder restarts along with the fresh encoder
+                        let resume = Control::Resume(codec).to_bytes();
+                        if let Err(err) = socket.send_to(&resume, *client_addr.lock().unwrap()) {
+                            error!("{}", err);
+                        }
+                        continue;
+                    }
+                };
+                let client = *client_addr.lock().unwrap();
+                let now = Instant::now();
+                // Probes go out silent or not, the receiver echoes them to the address port
+                if now.duration_since(last_probe) >= PROBE_INTERVAL {
+                    last_probe = now;
+                    probe_id += 1;
+                    let probe = clock.probe(probe_id, now);
+                    if let Err(err) = socket.send_to(&probe.to_bytes(), client) {
+                        error!("{}", err);
+                    }
                 }
-                let lat = pulse_cnn
-                    .get_latency()
-                    .unwrap_or(libpulse_binding::time::MicroSeconds::from_secs_f32(0.0));
-                if lat > libpulse_binding::time::MicroSeconds(0) {
-                    info!("Latancy: {lat}");
+                if now.duration_since(last_log) >= LOG_INTERVAL {
+                    last_log = now;
+                    let mut budget = budget(codec);
+                    let capture = source.latency();
+                    if capture > Duration::ZERO {
+                        budget.capture = capture;
+                    }
+                    let mut latencies = latencies.lock().unwrap();
+                    latencies.retain(|_, latency| !latency.is_stale(now));
+                    for (ip, latency) in latencies.iter() {
+                        info!("Latency to {ip}: {}", latency.estimate(budget));
+                    }
                 }
-                let client = *client_addr.lock().unwrap();
-                if args.with_aptx {
-                    let mut written = 0usize;
-                    let processed = ctx.encode(&buffer, &mut enc_buffer, &mut written);
-                    if processed != buffer.len() {
-                        error!(
-                            "Fail to encode processed {} out of {}",
-                            processed,
-                            buffer.len()
-                        );
+                // Push volume changes right away, silent or not, and repeat it with the codec
+                let current_volume = settings.volume();
+                if sent_volume != Some(current_volume) || count % codec_announce_packets == 0 {
+                    if sent_volume.is_some_and(|sent| sent != current_volume) {
+                        info!("Volume {current_volume}");
+                    }
+                    sent_volume = Some(current_volume);
+                    let control = Control::Volume(current_volume);
+                    if let Err(err) = socket.send_to(&control.to_bytes(), client) {
+                        error!("{}", err);
+                    }
+                }
+                let marker = match outgoing {
+                    Outgoing::Silence { packets } => {
+                        if packets == 0 {
+                            debug!("Silence, pausing the stream");
+                        }
+                        if packets % silence_keepalive_packets == 0 {
+                            let keepalive = Control::Silence {
+                                frames: frames_per_packet as u16,
+                            };
+                            if let Err(err) = socket.send_to(&keepalive.to_bytes(), client) {
+                                error!("{}", err);
+                            }
+                        }
+                        continue;
                     }
-                    match socket.send_to(&enc_buffer, client) {
-                        Ok(nbytes) => debug!("Sending to {:?} {}", client, nbytes),
-                        Err(err) => error!("{}", err),
+                    Outgoing::Audio {
+                        marker,
+                        resumed_after,
+                    } => {
+                        if resumed_after > 0 {
+                            debug!("Audio after {resumed_after} silent packets");
+                        }
+                        marker
                     }
-                } else {
-                    match socket.send_to(&buffer, client) {
-                        Ok(nbytes) => debug!("Sending to {:?} {}", client, nbytes),
-                        Err(err) => error!("{}", err),
+                };
+                // Announce switches and restarts after silence, and repeat the codec now and
+                // then for receivers that joined late or lost the announcement
+                let marker = match marker {
+                    Some(Control::Codec(next)) => {
+                        info!("Switching codec {codec} -> {next}");
+                        info!("Latency budget: {}", budget(next));
+                        codec = next;
+                        marker
                     }
+                    Some(_) => marker,
+                    None => (count % codec_announce_packets == 0).then_some(Control::Codec(codec)),
+                };
+                if let Some(marker) = marker {
+                    if let Err(err) = socket.send_to(&marker.to_bytes(), client) {
+                        error!("{}", err);
+                    }
+                }
+                match socket.send_to(&packet, client) {
+                    Ok(nbytes) => debug!("Sending to {:?} {}", client, nbytes),
+                    Err(err) => error!("{}", err),
                 }
             }
         });
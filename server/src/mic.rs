@@ -0,0 +1,48 @@
+//! Microphone of the phone, played into a virtual source so any app on the PC can record it.
+
+use crate::{
+    sink::{AudioSink, PulseSink},
+    utils,
+};
+use aptx_rust::{
+    buffering::Buffering,
+    protocol::{Control, MIC_CODEC},
+    stream::{Packet, StreamDecoder},
+};
+use log::{error, info};
+use std::cell::RefCell;
+
+/// Name of the source apps record from.
+pub const MIC_SOURCE: &str = "pc_relay_mic";
+
+/// Decodes the packets received on `port` and plays them into the virtual microphone, buffered
+/// like a receiver would. The phone starts each stream with a resume marker, which restarts the
+/// decoder.
+pub fn relay_mic(addr: &str, port: u16, buffering: &Buffering) {
+    // Loaded again when the server restarted
+    let open = || {
+        let sink = utils::pulse_load_virtual_source(MIC_SOURCE, "Phone microphone")?;
+        PulseSink::new(
+            MIC_CODEC,
+            Some(&sink),
+            "Phone microphone",
+            buffering.playback_attr(2 * MIC_CODEC.bytes_per_sample()),
+        )
+    };
+    let output = RefCell::new(utils::retry("Creating the microphone source", open));
+    let decoder = RefCell::new(StreamDecoder::new(MIC_CODEC));
+    utils::udp_server_loop_bytes::<4096>(addr, port, |data, client| {
+        let mut decoder = decoder.borrow_mut();
+        match decoder.process(data) {
+            Packet::Audio(pcm) => {
+                let mut output = output.borrow_mut();
+                if let Err(err) = output.write(pcm) {
+                    error!("Fail to play the microphone: {err}");
+                    *output = utils::retry("Reopening the microphone source", open);
+                }
+            }
+            Packet::Control(Control::Resume(_)) => info!("Microphone from {client}"),
+            _ => {}
+        }
+    });
+}
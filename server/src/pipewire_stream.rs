@@ -0,0 +1,321 @@
+//! Native PipeWire streams, for the server capture and the `listen` playback, without the extra
+//! buffering of the pipewire-pulse layer. The PipeWire main loop runs on its own thread and
+//! trades audio with the blocking `read` / `write` side through a [`ring`], so the real-time
+//! process callback never allocates. The loop quits when the stream errors or disconnects, or
+//! the daemon goes away, which closes the ring and fails the blocking side.
+
+use crate::ring::{ring, RingReader, RingWriter};
+use log::warn;
+use pipewire as pw;
+use pw::spa;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    time::Duration,
+};
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: usize = 2;
+/// Packets the ring holds between the graph and the network side, capture drops beyond that
+/// and playback blocks.
+const QUEUE_PACKETS: usize = 4;
+
+fn audio_format(bytes_per_sample: usize) -> spa::param::audio::AudioFormat {
+    if bytes_per_sample == 3 {
+        spa::param::audio::AudioFormat::S24LE
+    } else {
+        spa::param::audio::AudioFormat::S16LE
+    }
+}
+
+/// Runs a stream on a new thread, `process` is called from the graph with each buffer. Returns
+/// once the stream is connected, with the sender that stops it.
+fn spawn_stream<F>(
+    target: Option<String>,
+    capture: bool,
+    bytes_per_sample: usize,
+    frames_per_packet: usize,
+    mut process: F,
+) -> io::Result<pw::channel::Sender<()>>
+where
+    F: FnMut(&mut spa::buffer::Data) + Send + 'static,
+{
+    let (ready_send, ready) = mpsc::channel();
+    let (stop, stop_receive) = pw::channel::channel();
+    std::thread::spawn(move || {
+        let run = || -> Result<(), pw::Error> {
+            pw::init();
+            let mainloop = pw::main_loop::MainLoop::new(None)?;
+            let context = pw::context::Context::new(&mainloop)?;
+            let core = context.connect(None)?;
+            let quit = mainloop.clone();
+            let _stop = stop_receive.attach(mainloop.loop_(), move |()| quit.quit());
+            // The daemon going away is an error on the core
+            let quit = mainloop.clone();
+            let _core_listener = core
+                .add_listener_local()
+                .error(move |id, _, _, message| {
+                    if id == pw::core::PW_ID_CORE {
+                        warn!("PipeWire: {message}");
+                        quit.quit();
+                    }
+                })
+                .register();
+
+            let mut props = pw::properties::Properties::new();
+            props.insert(*pw::keys::MEDIA_TYPE, "Audio");
+            props.insert(
+                *pw::keys::MEDIA_CATEGORY,
+                if capture { "Capture" } else { "Playback" },
+            );
+            props.insert(*pw::keys::MEDIA_ROLE, "Music");
+            props.insert(*pw::keys::APP_NAME, "pc_relay");
+            // Ask the graph for a quantum of one packet
+            props.insert(
+                *pw::keys::NODE_LATENCY,
+                format!("{frames_per_packet}/{SAMPLE_RATE}"),
+            );
+            props.insert(*pw::keys::NODE_RATE, format!("1/{SAMPLE_RATE}"));
+            if capture {
+                // Record what goes to the sink, not a microphone
+                props.insert(*pw::keys::STREAM_CAPTURE_SINK, "true");
+            }
+            if let Some(target) = &target {
+                props.insert(*pw::keys::TARGET_OBJECT, target.as_str());
+            }
+            let stream = pw::stream::Stream::new(&core, "System sound", props)?;
+            let quit = mainloop.clone();
+            let listener = stream
+                .add_local_listener_with_user_data(())
+                .state_changed(move |_, _, _, state| match state {
+                    pw::stream::StreamState::Error(err) => {
+                        warn!("PipeWire stream: {err}");
+                        quit.quit();
+                    }
+                    pw::stream::StreamState::Unconnected => quit.quit(),
+                    _ => {}
+                })
+                .process(move |stream, _| {
+                    if let Some(mut buffer) = stream.dequeue_buffer() {
+                        if let Some(data) = buffer.datas_mut().first_mut() {
+                            process(data);
+                        }
+                    }
+                })
+                .register()?;
+
+            let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+            audio_info.set_format(audio_format(bytes_per_sample));
+            audio_info.set_rate(SAMPLE_RATE);
+            audio_info.set_channels(CHANNELS as u32);
+            let values: Vec<u8> = spa::pod::serialize::PodSerializer::serialize(
+                io::Cursor::new(Vec::new()),
+                &spa::pod::Value::Object(spa::pod::Object {
+                    type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+                    id: spa::param::ParamType::EnumFormat.as_raw(),
+                    properties: audio_info.into(),
+                }),
+            )
+            .expect("Fail to serialize the stream format")
+            .0
+            .into_inner();
+            let mut params = [spa::pod::Pod::from_bytes(&values).unwrap()];
+            stream.connect(
+                if capture {
+                    spa::utils::Direction::Input
+                } else {
+                    spa::utils::Direction::Output
+                },
+                None,
+                pw::stream::StreamFlags::AUTOCONNECT
+                    | pw::stream::StreamFlags::MAP_BUFFERS
+                    | pw::stream::StreamFlags::RT_PROCESS,
+                &mut params,
+            )?;
+            let _ = ready_send.send(Ok(()));
+            mainloop.run();
+            // Drops `process` and with it the graph's end of the ring
+            drop(listener);
+            Ok(())
+        };
+        if let Err(err) = run() {
+            let _ = ready_send.send(Err(io::Error::other(err.to_string())));
+        }
+    });
+    ready
+        .recv()
+        .unwrap_or_else(|_| Err(io::Error::other("PipeWire thread exited")))?;
+    Ok(stop)
+}
+
+fn stream_stopped() -> io::Error {
+    io::Error::other("PipeWire stream stopped")
+}
+
+/// Bytes to queued time, for the latency queries.
+fn queued_duration(bytes: usize, bytes_per_sample: usize) -> Duration {
+    let frames = bytes / (CHANNELS * bytes_per_sample);
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
+pub struct PipeWireCapture {
+    ring: RingReader,
+    stop: pw::channel::Sender<()>,
+    overruns: Arc<AtomicUsize>,
+    bytes_per_sample: usize,
+}
+
+impl PipeWireCapture {
+    /// Records the monitor of the sink named `target`, or of the default sink.
+    pub fn new(
+        target: Option<String>,
+        bytes_per_sample: usize,
+        frames_per_packet: usize,
+    ) -> io::Result<PipeWireCapture> {
+        let (mut writer, ring) =
+            ring(QUEUE_PACKETS * frames_per_packet * CHANNELS * bytes_per_sample);
+        let overruns = Arc::new(AtomicUsize::new(0));
+        let graph_overruns = overruns.clone();
+        let stop = spawn_stream(
+            target,
+            true,
+            bytes_per_sample,
+            frames_per_packet,
+            move |data| {
+                let (offset, size) = (data.chunk().offset() as usize, data.chunk().size() as usize);
+                let Some(samples) = data.data() else {
+                    return;
+                };
+                let Some(samples) = samples.get(offset..offset + size) else {
+                    return;
+                };
+                // Whole chunks only, never block the graph, the reader is late if they do not fit
+                if writer.space() < size {
+                    graph_overruns.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                writer.write(samples);
+                writer.wake();
+            },
+        )?;
+        Ok(PipeWireCapture {
+            ring,
+            stop,
+            overruns,
+            bytes_per_sample,
+        })
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            filled += self.ring.read(&mut buffer[filled..]);
+            if filled < buffer.len() {
+                if self.ring.is_closed() {
+                    return Err(stream_stopped());
+                }
+                self.ring.wait();
+            }
+        }
+        let overruns = self.overruns.swap(0, Ordering::Relaxed);
+        if overruns > 0 {
+            warn!("PipeWire: dropped {overruns} chunks");
+        }
+        Ok(())
+    }
+
+    /// Captured audio not read yet.
+    pub fn latency(&self) -> Duration {
+        queued_duration(self.ring.available(), self.bytes_per_sample)
+    }
+}
+
+impl Drop for PipeWireCapture {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+    }
+}
+
+pub struct PipeWirePlayback {
+    ring: RingWriter,
+    stop: pw::channel::Sender<()>,
+    bytes_per_sample: usize,
+}
+
+impl PipeWirePlayback {
+    /// Plays on the sink named `target`, or on the default sink.
+    pub fn new(
+        target: Option<String>,
+        bytes_per_sample: usize,
+        frames_per_packet: usize,
+    ) -> io::Result<PipeWirePlayback> {
+        let stride = CHANNELS * bytes_per_sample;
+        let (ring, mut reader) = ring(QUEUE_PACKETS * frames_per_packet * stride);
+        let stop = spawn_stream(
+            target,
+            false,
+            bytes_per_sample,
+            frames_per_packet,
+            move |data| {
+                let Some(samples) = data.data() else {
+                    return;
+                };
+                // One quantum, what the stream asked the graph for, the mapped buffer is larger
+                let quantum = (frames_per_packet * stride).min(samples.len());
+                // Whole frames of what arrived, a short chunk rather than padding with silence
+                // that would pile up as latency
+                let size = reader.available().min(quantum) / stride * stride;
+                reader.read(&mut samples[..size]);
+                reader.wake();
+                let chunk = data.chunk_mut();
+                *chunk.offset_mut() = 0;
+                *chunk.stride_mut() = stride as i32;
+                *chunk.size_mut() = size as u32;
+            },
+        )?;
+        Ok(PipeWirePlayback {
+            ring,
+            stop,
+            bytes_per_sample,
+        })
+    }
+
+    /// Queues `pcm`, blocks while the ring is full.
+    pub fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < pcm.len() {
+            if self.ring.is_closed() {
+                return Err(stream_stopped());
+            }
+            written += self.ring.write(&pcm[written..]);
+            if written < pcm.len() {
+                self.ring.wait();
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the queued audio to be played, fails if the stream stops first.
+    pub fn drain(&mut self) -> io::Result<()> {
+        while self.ring.queued() > 0 {
+            if self.ring.is_closed() {
+                return Err(stream_stopped());
+            }
+            self.ring.wait();
+        }
+        Ok(())
+    }
+
+    pub fn latency(&self) -> Duration {
+        queued_duration(self.ring.queued(), self.bytes_per_sample)
+    }
+}
+
+impl Drop for PipeWirePlayback {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+    }
+}
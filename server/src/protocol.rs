@@ -0,0 +1,425 @@
+//! Packets exchanged between `server_pulse` and its receivers on the audio port.
+//!
+//! Audio packets carry the payload of the current codec as is. Control packets are short,
+//! start with [`CONTROL_MAGIC`] and are sent in band, between audio packets, so a receiver sees
+//! them in order with the audio they apply to.
+
+use crate::aptx::AptxContext;
+use std::{fmt::Display, str::FromStr};
+
+pub const SAMPLE_RATE: u32 = 48000;
+pub const CONTROL_MAGIC: [u8; 4] = *b"PCRC";
+/// Control packets are never longer than this, audio packets always are.
+pub const CONTROL_MAX_LEN: usize = 64;
+/// Codec of the microphone stream receivers send back to the server, in packets of the same
+/// format on its own port.
+pub const MIC_CODEC: Codec = Codec::Aptx;
+
+const CONTROL_CODEC: u8 = 1;
+const CONTROL_SILENCE: u8 = 2;
+const CONTROL_REPORT: u8 = 3;
+const CONTROL_VOLUME: u8 = 4;
+const CONTROL_PROBE: u8 = 5;
+const CONTROL_RESUME: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Pcm,
+    Aptx,
+    AptxHd,
+    /// aptX of the audio downsampled to 24 kHz, half the bitrate of aptX for links where even
+    /// that loses packets. Receivers get it back at 48 kHz.
+    AptxLow,
+}
+
+impl Codec {
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::Pcm => 0,
+            Codec::Aptx => 1,
+            Codec::AptxHd => 2,
+            Codec::AptxLow => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::Pcm),
+            1 => Some(Codec::Aptx),
+            2 => Some(Codec::AptxHd),
+            3 => Some(Codec::AptxLow),
+            _ => None,
+        }
+    }
+
+    pub fn is_aptx(self) -> bool {
+        self != Codec::Pcm
+    }
+
+    pub fn hd(self) -> bool {
+        self == Codec::AptxHd
+    }
+
+    /// Bytes per sample of the PCM going into the encoder and out of the decoder.
+    pub fn bytes_per_sample(self) -> usize {
+        if self.hd() {
+            3
+        } else {
+            2
+        }
+    }
+
+    /// Frames of the stream coded together, packets carry a multiple of them.
+    pub fn block_frames(self) -> usize {
+        match self {
+            Codec::Pcm => 1,
+            Codec::Aptx | Codec::AptxHd => 4,
+            Codec::AptxLow => 8,
+        }
+    }
+
+    /// Algorithmic delay in frames between the encoder input and the decoder output.
+    pub fn delay_frames(self) -> usize {
+        match self {
+            Codec::Pcm => 0,
+            Codec::Aptx | Codec::AptxHd => AptxContext::LATENCY_SAMPLES,
+            // The codec runs at half the rate, plus a frame for resampling
+            Codec::AptxLow => 2 * AptxContext::LATENCY_SAMPLES + 1,
+        }
+    }
+
+    /// Size of the packet carrying `frames` stereo frames.
+    pub fn packet_size(self, frames: usize) -> usize {
+        match self {
+            Codec::Pcm => frames * 4,
+            Codec::Aptx => frames,
+            Codec::AptxHd => frames / 4 * 6,
+            Codec::AptxLow => frames / 2,
+        }
+    }
+
+    /// Stereo frames carried by a packet of `size` bytes, the inverse of [`Codec::packet_size`].
+    pub fn packet_frames(self, size: usize) -> usize {
+        match self {
+            Codec::Pcm => size / 4,
+            Codec::Aptx => size,
+            Codec::AptxHd => size / 6 * 4,
+            Codec::AptxLow => size * 2,
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Pcm => write!(f, "PCM"),
+            Codec::Aptx => write!(f, "APTX"),
+            Codec::AptxHd => write!(f, "APTXHD"),
+            Codec::AptxLow => write!(f, "APTXLOW"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "PCM" | "RAW" => Ok(Codec::Pcm),
+            "APTX" => Ok(Codec::Aptx),
+            "APTXHD" | "APTX-HD" | "HD" => Ok(Codec::AptxHd),
+            "APTXLOW" | "APTX-LOW" | "LOW" => Ok(Codec::AptxLow),
+            other => Err(format!("Unknown codec {other}")),
+        }
+    }
+}
+
+/// Volume of the stream, applied by the server before encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    pub percent: u8,
+    pub muted: bool,
+}
+
+impl Volume {
+    pub const MAX: u8 = 100;
+    const STEP: u8 = 5;
+
+    pub fn apply(self, command: VolumeCommand) -> Volume {
+        match command {
+            VolumeCommand::Up => Volume {
+                percent: (self.percent + Self::STEP).min(Self::MAX),
+                muted: false,
+            },
+            VolumeCommand::Down => Volume {
+                percent: self.percent.saturating_sub(Self::STEP),
+                ..self
+            },
+            VolumeCommand::Set(percent) => Volume {
+                percent: percent.min(Self::MAX),
+                muted: false,
+            },
+            VolumeCommand::Mute => Volume {
+                muted: true,
+                ..self
+            },
+            VolumeCommand::Unmute => Volume {
+                muted: false,
+                ..self
+            },
+            VolumeCommand::ToggleMute => Volume {
+                muted: !self.muted,
+                ..self
+            },
+        }
+    }
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            percent: Self::MAX,
+            muted: false,
+        }
+    }
+}
+
+impl Display for Volume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.percent)?;
+        if self.muted {
+            write!(f, " (muted)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Argument of the `VOLUME` command of the command port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeCommand {
+    Up,
+    Down,
+    Set(u8),
+    Mute,
+    Unmute,
+    ToggleMute,
+}
+
+impl FromStr for VolumeCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "UP" | "+" => Ok(VolumeCommand::Up),
+            "DOWN" | "-" => Ok(VolumeCommand::Down),
+            "MUTE" => Ok(VolumeCommand::Mute),
+            "UNMUTE" => Ok(VolumeCommand::Unmute),
+            "TOGGLE" => Ok(VolumeCommand::ToggleMute),
+            other => other
+                .parse()
+                .map(VolumeCommand::Set)
+                .map_err(|_| format!("Unknown volume command {other}")),
+        }
+    }
+}
+
+/// What a receiver got since its previous report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub received: u32,
+    /// Estimated from the packets expected in the time elapsed.
+    pub lost: u32,
+    pub jitter_us: u32,
+    /// Audio queued in the receiver's playback buffer.
+    pub buffer_ms: u32,
+    /// Delay of the output after the playback buffer, 0 when the receiver cannot tell it apart
+    /// from `buffer_ms`. Older receivers do not send it.
+    pub playback_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    /// Following audio packets use this codec, the receiver must flush and reinitialize.
+    Codec(Codec),
+    /// Keep-alive sent instead of audio while the server hears silence, each one stands for
+    /// packets of `frames` frames that were not sent.
+    Silence { frames: u16 },
+    /// Receiver feedback, sent to the server's address port.
+    Report(Report),
+    /// Sent whenever the volume changes, and repeated with the codec.
+    Volume(Volume),
+    /// Latency probe, receivers echo it as is to the server's address port. `sent_us` is on the
+    /// server's clock, the echo gives it the round trip time.
+    Probe { id: u32, sent_us: u64 },
+    /// Audio comes back after silence from a fresh encoder of this codec, the receiver restarts
+    /// its decoder even if it missed the keep-alives.
+    Resume(Codec),
+}
+
+impl Control {
+    pub fn parse(packet: &[u8]) -> Option<Control> {
+        if packet.len() > CONTROL_MAX_LEN || packet.len() < 5 || packet[..4] != CONTROL_MAGIC {
+            return None;
+        }
+        let payload = &packet[5..];
+        match packet[4] {
+            CONTROL_CODEC => Codec::from_id(*payload.first()?).map(Control::Codec),
+            CONTROL_SILENCE => Some(Control::Silence {
+                frames: u16::from_le_bytes(payload.get(..2)?.try_into().ok()?),
+            }),
+            CONTROL_REPORT => {
+                let field = |n: usize| {
+                    let bytes = payload.get(n * 4..n * 4 + 4)?;
+                    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+                };
+                Some(Control::Report(Report {
+                    received: field(0)?,
+                    lost: field(1)?,
+                    jitter_us: field(2)?,
+                    buffer_ms: field(3)?,
+                    playback_ms: field(4).unwrap_or(0),
+                }))
+            }
+            CONTROL_VOLUME => match payload.get(..2)? {
+                &[percent, muted] if percent <= Volume::MAX => Some(Control::Volume(Volume {
+                    percent,
+                    muted: muted != 0,
+                })),
+                _ => None,
+            },
+            CONTROL_PROBE => Some(Control::Probe {
+                id: u32::from_le_bytes(payload.get(..4)?.try_into().ok()?),
+                sent_us: u64::from_le_bytes(payload.get(4..12)?.try_into().ok()?),
+            }),
+            CONTROL_RESUME => Codec::from_id(*payload.first()?).map(Control::Resume),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = CONTROL_MAGIC.to_vec();
+        match self {
+            Control::Codec(codec) => packet.extend_from_slice(&[CONTROL_CODEC, codec.id()]),
+            Control::Resume(codec) => packet.extend_from_slice(&[CONTROL_RESUME, codec.id()]),
+            Control::Silence { frames } => {
+                packet.push(CONTROL_SILENCE);
+                packet.extend_from_slice(&frames.to_le_bytes());
+            }
+            Control::Report(report) => {
+                packet.push(CONTROL_REPORT);
+                for field in [
+                    report.received,
+                    report.lost,
+                    report.jitter_us,
+                    report.buffer_ms,
+                    report.playback_ms,
+                ] {
+                    packet.extend_from_slice(&field.to_le_bytes());
+                }
+            }
+            Control::Volume(volume) => {
+                packet.extend_from_slice(&[CONTROL_VOLUME, volume.percent, volume.muted as u8])
+            }
+            Control::Probe { id, sent_us } => {
+                packet.push(CONTROL_PROBE);
+                packet.extend_from_slice(&id.to_le_bytes());
+                packet.extend_from_slice(&sent_us.to_le_bytes());
+            }
+        }
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_roundtrip() {
+        for codec in [Codec::Pcm, Codec::Aptx, Codec::AptxHd, Codec::AptxLow] {
+            let control = Control::Codec(codec);
+            assert_eq!(Control::parse(&control.to_bytes()), Some(control));
+            let control = Control::Resume(codec);
+            assert_eq!(Control::parse(&control.to_bytes()), Some(control));
+        }
+        let control = Control::Silence { frames: 512 };
+        assert_eq!(Control::parse(&control.to_bytes()), Some(control));
+        let control = Control::Report(Report {
+            received: 90,
+            lost: 4,
+            jitter_us: 1200,
+            buffer_ms: 21,
+            playback_ms: 12,
+        });
+        assert_eq!(Control::parse(&control.to_bytes()), Some(control));
+        let control = Control::Probe {
+            id: 7,
+            sent_us: 1 << 40,
+        };
+        assert_eq!(Control::parse(&control.to_bytes()), Some(control));
+    }
+
+    #[test]
+    fn test_report_without_playback() {
+        let mut packet = Control::Report(Report {
+            received: 90,
+            lost: 0,
+            jitter_us: 800,
+            buffer_ms: 21,
+            playback_ms: 12,
+        })
+        .to_bytes();
+        packet.truncate(packet.len() - 4);
+        let Some(Control::Report(report)) = Control::parse(&packet) else {
+            panic!("Report not parsed");
+        };
+        assert_eq!((report.buffer_ms, report.playback_ms), (21, 0));
+    }
+
+    #[test]
+    fn test_control_rejects_audio() {
+        assert_eq!(Control::parse(&[0; 512]), None);
+        let mut packet = Control::Codec(Codec::Aptx).to_bytes();
+        packet.resize(512, 0);
+        assert_eq!(Control::parse(&packet), None);
+        assert_eq!(Control::parse(b"PCRC"), None);
+        assert_eq!(Control::parse(b"PCRC\x02\x00"), None);
+    }
+
+    #[test]
+    fn test_volume_commands() {
+        let volume = Volume::default();
+        assert_eq!("up".parse(), Ok(VolumeCommand::Up));
+        assert_eq!(" 40".parse(), Ok(VolumeCommand::Set(40)));
+        assert!("loud".parse::<VolumeCommand>().is_err());
+        assert_eq!(volume.apply(VolumeCommand::Up).percent, 100);
+        let volume = volume
+            .apply(VolumeCommand::Set(42))
+            .apply(VolumeCommand::Down);
+        assert_eq!(volume.percent, 37);
+        let volume = volume.apply(VolumeCommand::ToggleMute);
+        assert!(volume.muted);
+        assert_eq!(volume.to_string(), "37% (muted)");
+        assert!(!volume.apply(VolumeCommand::Up).muted);
+
+        let control = Control::Volume(volume);
+        assert_eq!(Control::parse(&control.to_bytes()), Some(control));
+        assert_eq!(Control::parse(b"PCRC\x04\xff\x00"), None);
+    }
+
+    #[test]
+    fn test_packet_frames() {
+        for codec in [Codec::Pcm, Codec::Aptx, Codec::AptxHd, Codec::AptxLow] {
+            assert_eq!(codec.packet_frames(codec.packet_size(480)), 480);
+        }
+    }
+
+    #[test]
+    fn test_codec_from_str() {
+        assert_eq!("aptx".parse(), Ok(Codec::Aptx));
+        assert_eq!("APTXHD\0".trim_matches('\0').parse(), Ok(Codec::AptxHd));
+        assert_eq!(Codec::AptxHd.to_string().parse(), Ok(Codec::AptxHd));
+        assert_eq!(Codec::AptxLow.to_string().parse(), Ok(Codec::AptxLow));
+        assert!("opus".parse::<Codec>().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Lock-free single producer, single consumer byte ring between a real-time audio callback and
+//! the blocking network side. The callback never blocks, locks or allocates, it moves what fits
+//! and wakes the other side, which parks in between. Dropping either end closes the ring, so the
+//! blocking side notices a stream that went away instead of waiting forever.
+
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    thread::Thread,
+    time::Duration,
+};
+
+/// Longest park of the blocking side, in case the thread waiting is not the one registered.
+const WAIT: Duration = Duration::from_millis(20);
+
+struct Shared {
+    buffer: Box<[UnsafeCell<u8>]>,
+    /// Bytes read and written since the start, only ever increased by their own end
+    read: AtomicUsize,
+    write: AtomicUsize,
+    closed: AtomicBool,
+    /// Thread woken when the other end moves data, the first one to wait
+    waiter: OnceLock<Thread>,
+}
+
+// The reader only touches the bytes between `read` and `write`, the writer the rest
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn len(&self) -> usize {
+        self.write
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+
+    fn slot(&self, position: usize) -> *mut u8 {
+        self.buffer[position % self.buffer.len()].get()
+    }
+
+    fn wake(&self) {
+        if let Some(thread) = self.waiter.get() {
+            thread.unpark();
+        }
+    }
+
+    fn wait(&self) {
+        self.waiter.get_or_init(std::thread::current);
+        std::thread::park_timeout(WAIT);
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wake();
+    }
+}
+
+/// A ring of `capacity` bytes, split into its two ends.
+pub fn ring(capacity: usize) -> (RingWriter, RingReader) {
+    let shared = Arc::new(Shared {
+        buffer: (0..capacity.max(1)).map(|_| UnsafeCell::new(0)).collect(),
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        waiter: OnceLock::new(),
+    });
+    (
+        RingWriter {
+            shared: shared.clone(),
+        },
+        RingReader { shared },
+    )
+}
+
+pub struct RingWriter {
+    shared: Arc<Shared>,
+}
+
+impl RingWriter {
+    /// Bytes written and not read yet.
+    pub fn queued(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn space(&self) -> usize {
+        self.shared.buffer.len() - self.queued()
+    }
+
+    /// Copies as much of `data` as fits, returns how much.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let count = data.len().min(self.space());
+        let start = self.shared.write.load(Ordering::Relaxed);
+        for (i, &byte) in data[..count].iter().enumerate() {
+            unsafe { *self.shared.slot(start.wrapping_add(i)) = byte };
+        }
+        self.shared
+            .write
+            .store(start.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Whether the reader is gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Wakes the thread waiting on the other end.
+    pub fn wake(&self) {
+        self.shared.wake();
+    }
+
+    /// Parks until the reader moves data, closes the ring or a short timeout passes.
+    pub fn wait(&self) {
+        self.shared.wait();
+    }
+}
+
+impl Drop for RingWriter {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+pub struct RingReader {
+    shared: Arc<Shared>,
+}
+
+impl RingReader {
+    /// Bytes ready to be read.
+    pub fn available(&self) -> usize {
+        self.shared.len()
+    }
+
+    /// Fills as much of `output` as is available, returns how much.
+    pub fn read(&mut self, output: &mut [u8]) -> usize {
+        let count = output.len().min(self.available());
+        let start = self.shared.read.load(Ordering::Relaxed);
+        for (i, byte) in output[..count].iter_mut().enumerate() {
+            *byte = unsafe { *self.shared.slot(start.wrapping_add(i)) };
+        }
+        self.shared
+            .read
+            .store(start.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Whether the writer is gone, what is left can still be read.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Wakes the thread waiting on the other end.
+    pub fn wake(&self) {
+        self.shared.wake();
+    }
+
+    /// Parks until the writer moves data, closes the ring or a short timeout passes.
+    pub fn wait(&self) {
+        self.shared.wait();
+    }
+}
+
+impl Drop for RingReader {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_wraps() {
+        let (mut writer, mut reader) = ring(8);
+        let mut output = [0; 8];
+        for round in 0..5u8 {
+            let data = [round; 6];
+            assert_eq!(writer.write(&data), 6);
+            assert_eq!(writer.space(), 2);
+            assert_eq!(reader.read(&mut output[..4]), 4);
+            assert_eq!(reader.read(&mut output[4..]), 2);
+            assert_eq!(output[..6], data);
+        }
+        assert_eq!(writer.write(&[1; 12]), 8);
+        assert_eq!(reader.available(), 8);
+    }
+
+    #[test]
+    fn test_ring_across_threads() {
+        let (mut writer, mut reader) = ring(64);
+        let producer = std::thread::spawn(move || {
+            let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+            let mut written = 0;
+            while written < data.len() {
+                written += writer.write(&data[written..]);
+                writer.wake();
+                std::thread::yield_now();
+            }
+        });
+        let mut received = Vec::new();
+        let mut chunk = [0; 48];
+        while !(reader.is_closed() && reader.available() == 0) {
+            let read = reader.read(&mut chunk);
+            received.extend_from_slice(&chunk[..read]);
+            if read == 0 {
+                reader.wait();
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(received.len(), 10_000);
+        assert!(received.iter().enumerate().all(|(i, &b)| b == i as u8));
+    }
+}
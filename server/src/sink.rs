@@ -0,0 +1,365 @@
+//! Where `listen` plays the stream. Sinks take interleaved stereo at 48 kHz, 16 bit or 24 bit
+//! depending on the codec, and are reopened when that changes.
+
+use aptx_rust::{
+    buffering::{Buffering, PulseBuffer, PulseOverrides},
+    protocol::{Codec, Control},
+    stream::{Packet, StreamDecoder},
+};
+use log::{error, warn};
+use std::{io, str::FromStr, time::Duration};
+
+/// What `listen` hands every packet it receives to, audio and control alike.
+pub trait PacketSink {
+    /// Blocks until `packet` is queued for playback.
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+    /// Audio queued but not played yet.
+    fn latency(&self) -> Duration;
+    /// Delay of the output after the queued audio, zero when it is part of [`Self::latency`].
+    fn playback_latency(&self) -> Duration;
+}
+
+pub trait AudioSink {
+    /// Blocks until `pcm` is queued for playback.
+    fn write(&mut self, pcm: &[u8]) -> io::Result<()>;
+    /// Blocks until everything queued was played.
+    fn drain(&mut self) -> io::Result<()>;
+    /// Audio queued but not played yet.
+    fn latency(&self) -> Duration;
+    /// Delay of the output after the queued audio, zero when it is part of [`Self::latency`].
+    fn playback_latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Value of `--sink`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkSpec {
+    /// PulseAudio sink matching this name or description, or the default sink
+    Pulse(Option<String>),
+    /// PipeWire node with this name, or the default sink
+    #[cfg(feature = "pipewire")]
+    PipeWire(Option<String>),
+    /// ALSA playback device, `default` when not given
+    #[cfg(feature = "alsa")]
+    Alsa(String),
+    /// JACK client with this name, `pc_relay` when not given
+    #[cfg(feature = "jack")]
+    Jack(String),
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+        match (kind, arg) {
+            ("pulse", "") => Ok(SinkSpec::Pulse(None)),
+            ("pulse", sink) => Ok(SinkSpec::Pulse(Some(sink.to_string()))),
+            #[cfg(feature = "pipewire")]
+            ("pipewire", node) => Ok(SinkSpec::PipeWire(
+                (!node.is_empty()).then(|| node.to_string()),
+            )),
+            #[cfg(not(feature = "pipewire"))]
+            ("pipewire", _) => Err("Built without the pipewire feature".to_string()),
+            #[cfg(feature = "alsa")]
+            ("alsa", "") => Ok(SinkSpec::Alsa("default".to_string())),
+            #[cfg(feature = "alsa")]
+            ("alsa", device) => Ok(SinkSpec::Alsa(device.to_string())),
+            #[cfg(not(feature = "alsa"))]
+            ("alsa", _) => Err("Built without the alsa feature".to_string()),
+            #[cfg(feature = "jack")]
+            ("jack", "") => Ok(SinkSpec::Jack("pc_relay".to_string())),
+            #[cfg(feature = "jack")]
+            ("jack", name) => Ok(SinkSpec::Jack(name.to_string())),
+            #[cfg(not(feature = "jack"))]
+            ("jack", _) => Err("Built without the jack feature".to_string()),
+            _ => Err(format!(
+                "Unknown sink {s}, expected pulse[:sink], pipewire[:node], alsa[:device] or jack[:client]"
+            )),
+        }
+    }
+}
+
+impl SinkSpec {
+    /// Opens the sink for packets of `codec` with buffers of `buffering.playback_packets`.
+    /// `pulse` only applies to PulseAudio. An output already open on the same device has to be
+    /// dropped first.
+    pub fn open(
+        &self,
+        codec: Codec,
+        buffering: &Buffering,
+        pulse: &PulseOverrides,
+    ) -> Result<Box<dyn PacketSink>, String> {
+        // Decodes in its process callback
+        #[cfg(feature = "jack")]
+        if let SinkSpec::Jack(name) = self {
+            return Ok(Box::new(
+                crate::jack_client::JackPlayback::new(name, codec, buffering.packet_frames)
+                    .map_err(|err| format!("Fail to start the JACK client: {err}"))?,
+            ));
+        }
+        Ok(Box::new(Decoding {
+            output: self.open_pcm(codec, buffering, pulse)?,
+            decoder: StreamDecoder::new(codec),
+            sink: self.clone(),
+            buffering: *buffering,
+            pulse: *pulse,
+        }))
+    }
+
+    #[cfg_attr(not(feature = "alsa"), allow(unused_variables))]
+    fn open_pcm(
+        &self,
+        codec: Codec,
+        buffering: &Buffering,
+        pulse: &PulseOverrides,
+    ) -> Result<Box<dyn AudioSink>, String> {
+        let bytes_per_frame = 2 * codec.bytes_per_sample();
+        Ok(match self {
+            SinkSpec::Pulse(pattern) => {
+                let device = pattern
+                    .as_deref()
+                    .map(crate::utils::pulse_get_sink_by_name)
+                    .transpose()?;
+                Box::new(PulseSink::new(
+                    codec,
+                    device.as_deref(),
+                    "System sound",
+                    pulse.apply(buffering.playback_attr(bytes_per_frame)),
+                )?)
+            }
+            #[cfg(feature = "pipewire")]
+            SinkSpec::PipeWire(node) => Box::new(
+                crate::pipewire_stream::PipeWirePlayback::new(
+                    node.clone(),
+                    codec.bytes_per_sample(),
+                    buffering.packet_frames,
+                )
+                .map_err(|err| format!("Fail to connect to PipeWire: {err}"))?,
+            ),
+            #[cfg(feature = "alsa")]
+            SinkSpec::Alsa(device) => Box::new(
+                crate::alsa_pcm::AlsaPlayback::new(
+                    device,
+                    codec.bytes_per_sample(),
+                    buffering.packet_frames,
+                    // ALSA needs two periods to play one while the other is written
+                    buffering.playback_packets.max(2),
+                )
+                .map_err(|err| format!("Fail to open the playback device: {err}"))?,
+            ),
+            #[cfg(feature = "jack")]
+            SinkSpec::Jack(_) => return Err("JACK takes packets, not PCM".to_string()),
+        })
+    }
+}
+
+/// Decodes on `listen`'s network thread and plays the PCM to an [`AudioSink`], which is
+/// reopened when the codec changes its sample size or when it fails.
+pub struct Decoding {
+    output: Box<dyn AudioSink>,
+    decoder: StreamDecoder,
+    sink: SinkSpec,
+    buffering: Buffering,
+    pulse: PulseOverrides,
+}
+
+impl Decoding {
+    /// Closes the output and opens it again, in that order since devices like ALSA `hw:` are
+    /// exclusive and the new one would fail while the old one is held.
+    fn reopen(&mut self, codec: Codec) {
+        self.output = Box::new(Closed);
+        self.output = crate::utils::retry("Reopening the output", || {
+            self.sink.open_pcm(codec, &self.buffering, &self.pulse)
+        });
+    }
+}
+
+impl PacketSink for Decoding {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        let playing = self.decoder.codec();
+        let result = match self.decoder.process(packet) {
+            Packet::Audio(pcm) | Packet::Silence(pcm) if !pcm.is_empty() => self.output.write(pcm),
+            Packet::Control(Control::Codec(codec) | Control::Resume(codec))
+                if codec.hd() != playing.hd() =>
+            {
+                let _ = self.output.drain();
+                self.reopen(codec);
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            error!("Fail to write audio: {err}");
+            self.reopen(playing);
+        }
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        self.output.latency()
+    }
+
+    fn playback_latency(&self) -> Duration {
+        self.output.playback_latency()
+    }
+}
+
+/// Stands in for an output being reopened.
+struct Closed;
+
+impl AudioSink for Closed {
+    fn write(&mut self, _: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+pub struct PulseSink {
+    simple: libpulse_simple_binding::Simple,
+    /// Latency of the sink's device, part of the stream's
+    device_latency: Duration,
+}
+
+impl PulseSink {
+    /// Plays to the sink `device`, or the default sink, as a stream named `description`.
+    pub fn new(
+        codec: Codec,
+        device: Option<&str>,
+        description: &str,
+        attr: PulseBuffer,
+    ) -> Result<PulseSink, String> {
+        use libpulse_binding::{def::BufferAttr, sample, stream::Direction};
+        use libpulse_simple_binding::Simple;
+
+        let format = if codec.hd() {
+            sample::Format::S24le
+        } else {
+            sample::Format::S16le
+        };
+        let audio_spec = sample::Spec {
+            format,
+            rate: 48000,
+            channels: 2,
+        };
+        let attr = BufferAttr {
+            maxlength: attr.maxlength,
+            tlength: attr.tlength,
+            prebuf: attr.prebuf,
+            minreq: attr.minreq,
+            fragsize: attr.fragsize,
+        };
+        let simple = Simple::new(
+            None,
+            "pc_relay",
+            Direction::Playback,
+            device,
+            description,
+            &audio_spec,
+            None,
+            Some(&attr),
+        )
+        .map_err(|err| format!("Fail to connect to the audio server: {err}"))?;
+        let device_latency = crate::utils::pulse_sink_latency(device).unwrap_or_else(|err| {
+            warn!("Fail to get the sink latency: {err}");
+            Duration::ZERO
+        });
+        Ok(PulseSink {
+            simple,
+            device_latency,
+        })
+    }
+}
+
+impl AudioSink for PulseSink {
+    fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        self.simple
+            .write(pcm)
+            .map_err(|err| io::Error::other(format!("{err}")))
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        self.simple
+            .drain()
+            .map_err(|err| io::Error::other(format!("{err}")))
+    }
+
+    fn latency(&self) -> Duration {
+        self.simple
+            .get_latency()
+            .map_or(Duration::ZERO, |lat| Duration::from_micros(lat.0))
+            .saturating_sub(self.device_latency)
+    }
+
+    fn playback_latency(&self) -> Duration {
+        self.device_latency
+    }
+}
+
+#[cfg(feature = "pipewire")]
+impl AudioSink for crate::pipewire_stream::PipeWirePlayback {
+    fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        crate::pipewire_stream::PipeWirePlayback::write(self, pcm)
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        crate::pipewire_stream::PipeWirePlayback::drain(self)
+    }
+
+    fn latency(&self) -> Duration {
+        crate::pipewire_stream::PipeWirePlayback::latency(self)
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl AudioSink for crate::alsa_pcm::AlsaPlayback {
+    fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        crate::alsa_pcm::AlsaPlayback::write(self, pcm)
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        crate::alsa_pcm::AlsaPlayback::drain(self)
+    }
+
+    fn latency(&self) -> Duration {
+        crate::alsa_pcm::AlsaPlayback::latency(self)
+    }
+}
+
+#[cfg(feature = "jack")]
+impl PacketSink for crate::jack_client::JackPlayback {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        crate::jack_client::JackPlayback::send(self, packet)
+    }
+
+    fn latency(&self) -> Duration {
+        crate::jack_client::JackPlayback::latency(self)
+    }
+
+    fn playback_latency(&self) -> Duration {
+        crate::jack_client::JackPlayback::playback_latency(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sink() {
+        assert_eq!("pulse".parse(), Ok(SinkSpec::Pulse(None)));
+        assert_eq!(
+            "pulse:hdmi".parse(),
+            Ok(SinkSpec::Pulse(Some("hdmi".to_string())))
+        );
+        assert!("oss".parse::<SinkSpec>().is_err());
+    }
+}
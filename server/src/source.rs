@@ -0,0 +1,783 @@
+//! Where the server gets the audio it streams from. Every source hands out interleaved frames
+//! in its [`SourceFormat`], the capture loop reads one packet at a time.
+
+use crate::wav::Wav;
+use aptx_rust::{
+    buffering::{Buffering, PulseBuffer, PulseOverrides},
+    stream::{Outgoing, StreamSettings, Transmitter},
+};
+use log::{error, info, warn};
+use std::{
+    f64::consts::PI,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceFormat {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bytes_per_sample: u8,
+}
+
+impl SourceFormat {
+    /// What the stream is made of, 24 bit stereo at 48 kHz.
+    pub const STREAM: SourceFormat = SourceFormat {
+        sample_rate: 48000,
+        channels: 2,
+        bytes_per_sample: 3,
+    };
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.channels as usize * self.bytes_per_sample as usize
+    }
+
+    pub fn duration(&self, frames: usize) -> Duration {
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+}
+
+impl Display for SourceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} Hz, {} channels, {} bits",
+            self.sample_rate,
+            self.channels,
+            self.bytes_per_sample * 8
+        )
+    }
+}
+
+pub trait AudioSource: Send {
+    /// Blocks until `buffer` is filled with whole frames.
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<()>;
+    /// Audio captured but not read yet.
+    fn latency(&self) -> Duration;
+    fn format(&self) -> SourceFormat;
+}
+
+/// What the capture loop streams from: packets already through a [`Transmitter`].
+pub trait PacketSource: Send {
+    /// Blocks until the next packet is captured, leaves its encoded audio in `packet`.
+    fn next(&mut self, packet: &mut Vec<u8>) -> io::Result<Outgoing>;
+    /// Audio captured but not sent yet.
+    fn latency(&self) -> Duration;
+}
+
+/// Runs the transmitter on the capture loop's thread, after a blocking read of the source.
+pub struct Encoding {
+    source: Box<dyn AudioSource>,
+    transmitter: Transmitter,
+    settings: Arc<StreamSettings>,
+    buffer: Vec<u8>,
+}
+
+impl PacketSource for Encoding {
+    fn next(&mut self, packet: &mut Vec<u8>) -> io::Result<Outgoing> {
+        self.source.read(&mut self.buffer)?;
+        Ok(self
+            .transmitter
+            .process(&mut self.buffer, &self.settings, packet))
+    }
+
+    fn latency(&self) -> Duration {
+        self.source.latency()
+    }
+}
+
+/// Name of the null sink of `--source phone`.
+pub const PHONE_SINK: &str = "pc_relay";
+
+/// Value of `--source`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceSpec {
+    /// PulseAudio source with this name or description, or the monitor of the default sink
+    Pulse(Option<String>),
+    /// Monitor of a null sink the server loads for itself, shown as "Phone" in mixers
+    Phone,
+    /// Streams of one application moved to the null sink of `phone`
+    App(crate::utils::AppMatch),
+    /// Digital silence, paced in real time
+    Silence,
+    /// Sine wave of this frequency, paced in real time
+    Sine(f64),
+    /// Logarithmic sweep from 20 Hz to 20 kHz lasting this many seconds, repeated
+    Sweep(f64),
+    /// Pink noise
+    Pink,
+    /// One millisecond click every this many milliseconds, to measure latency
+    Clicks(u64),
+    /// WAV or raw files played one after the other, looped
+    Files(Vec<PathBuf>),
+    /// Monitor of the PipeWire sink with this node name, or of the default sink
+    #[cfg(feature = "pipewire")]
+    PipeWire(Option<String>),
+    /// ALSA capture device, `default` when not given
+    #[cfg(feature = "alsa")]
+    Alsa(String),
+    /// JACK client with this name, `pc_relay` when not given
+    #[cfg(feature = "jack")]
+    Jack(String),
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+        match (kind, arg) {
+            ("pulse", "") => Ok(SourceSpec::Pulse(None)),
+            ("pulse", pattern) => Ok(SourceSpec::Pulse(Some(pattern.to_string()))),
+            ("phone", "") => Ok(SourceSpec::Phone),
+            ("app", "") => Err("Missing application name or pid".to_string()),
+            ("app", app) => Ok(SourceSpec::App(match app.parse() {
+                Ok(pid) => crate::utils::AppMatch::Pid(pid),
+                Err(_) => crate::utils::AppMatch::Name(app.to_string()),
+            })),
+            ("silence", "") => Ok(SourceSpec::Silence),
+            ("sine", "") => Ok(SourceSpec::Sine(1000.0)),
+            ("sine", freq) => freq
+                .parse()
+                .map(SourceSpec::Sine)
+                .map_err(|_| format!("Invalid frequency {freq}")),
+            ("sweep", "") => Ok(SourceSpec::Sweep(10.0)),
+            ("sweep", secs) => match secs.parse() {
+                Ok(secs) if secs > 0.0 => Ok(SourceSpec::Sweep(secs)),
+                _ => Err(format!("Invalid sweep duration {secs}")),
+            },
+            ("pink", "") => Ok(SourceSpec::Pink),
+            ("click", "") => Ok(SourceSpec::Clicks(1000)),
+            ("click", interval) => match interval.parse() {
+                Ok(interval) if interval > 0 => Ok(SourceSpec::Clicks(interval)),
+                _ => Err(format!("Invalid click interval {interval}")),
+            },
+            ("file", "") => Err("Missing file name".to_string()),
+            ("file", files) => Ok(SourceSpec::Files(
+                files.split(',').map(PathBuf::from).collect(),
+            )),
+            #[cfg(feature = "pipewire")]
+            ("pipewire", node) => Ok(SourceSpec::PipeWire(
+                (!node.is_empty()).then(|| node.to_string()),
+            )),
+            #[cfg(not(feature = "pipewire"))]
+            ("pipewire", _) => Err("Built without the pipewire feature".to_string()),
+            #[cfg(feature = "alsa")]
+            ("alsa", "") => Ok(SourceSpec::Alsa("default".to_string())),
+            #[cfg(feature = "alsa")]
+            ("alsa", device) => Ok(SourceSpec::Alsa(device.to_string())),
+            #[cfg(not(feature = "alsa"))]
+            ("alsa", _) => Err("Built without the alsa feature".to_string()),
+            #[cfg(feature = "jack")]
+            ("jack", "") => Ok(SourceSpec::Jack("pc_relay".to_string())),
+            #[cfg(feature = "jack")]
+            ("jack", name) => Ok(SourceSpec::Jack(name.to_string())),
+            #[cfg(not(feature = "jack"))]
+            ("jack", _) => Err("Built without the jack feature".to_string()),
+            _ => Err(format!(
+                "Unknown source {s}, expected pulse[:source], phone, app:name|pid, pipewire[:node], alsa[:device], jack[:client], \
+                 file:path[,path...], silence, sine[:freq], sweep[:secs], pink or click[:ms]"
+            )),
+        }
+    }
+}
+
+impl SourceSpec {
+    /// Sink the source is the monitor of, whose volume is the stream's, `None` for sources that
+    /// are not. Resolved again on every call, the default sink can change.
+    pub fn monitored_sink(&self) -> Option<Result<String, String>> {
+        match self {
+            SourceSpec::Pulse(pattern) => {
+                Some(crate::utils::pulse_monitored_sink(pattern.as_deref()))
+            }
+            SourceSpec::Phone | SourceSpec::App(_) => Some(Ok(PHONE_SINK.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Opens the source to be captured `buffering.packet_frames` at a time through
+    /// `transmitter`, with a buffer of `buffering.capture_packets`. `pulse` only applies to
+    /// PulseAudio.
+    pub fn open(
+        &self,
+        buffering: &Buffering,
+        pulse: &PulseOverrides,
+        transmitter: Transmitter,
+        settings: Arc<StreamSettings>,
+    ) -> Result<Box<dyn PacketSource>, String> {
+        let frames_per_packet = buffering.packet_frames;
+        let attr = pulse.apply(buffering.capture_attr(SourceFormat::STREAM.bytes_per_frame()));
+        let source: Box<dyn AudioSource> = match self {
+            SourceSpec::Pulse(pattern) => Box::new(PulseSource::new(pattern.as_deref(), attr)?),
+            SourceSpec::Phone | SourceSpec::App(_) => {
+                let load_sink = || crate::utils::pulse_load_null_sink(PHONE_SINK, "Phone");
+                let monitor = load_sink()?;
+                if let SourceSpec::App(app) = self {
+                    crate::utils::pulse_capture_app(app.clone(), PHONE_SINK);
+                }
+                Box::new(
+                    PulseSource::new(Some(&monitor), attr)?
+                        .with_setup(move || load_sink().map(drop)),
+                )
+            }
+            SourceSpec::Silence => Box::new(GeneratorSource::new(|_| 0.0)),
+            &SourceSpec::Sine(freq) => Box::new(GeneratorSource::new(move |t| {
+                0.5 * (2.0 * PI * freq * t).sin()
+            })),
+            &SourceSpec::Sweep(secs) => Box::new(GeneratorSource::new(move |t| {
+                0.5 * sweep_phase(t % secs, secs).sin()
+            })),
+            SourceSpec::Pink => Box::new(GeneratorSource::new(pink_noise())),
+            &SourceSpec::Clicks(interval) => Box::new(GeneratorSource::new(move |t| {
+                let since_click = t * 1000.0 % interval as f64;
+                if since_click < 1.0 {
+                    0.9
+                } else {
+                    0.0
+                }
+            })),
+            SourceSpec::Files(paths) => Box::new(
+                FileSource::new(paths).map_err(|err| format!("Fail to load the files: {err}"))?,
+            ),
+            #[cfg(feature = "pipewire")]
+            SourceSpec::PipeWire(node) => Box::new(
+                crate::pipewire_stream::PipeWireCapture::new(
+                    node.clone(),
+                    SourceFormat::STREAM.bytes_per_sample as usize,
+                    frames_per_packet,
+                )
+                .map_err(|err| format!("Fail to connect to PipeWire: {err}"))?,
+            ),
+            #[cfg(feature = "alsa")]
+            SourceSpec::Alsa(device) => Box::new(
+                crate::alsa_pcm::AlsaCapture::new(
+                    device,
+                    SourceFormat::STREAM.bytes_per_sample as usize,
+                    frames_per_packet,
+                    buffering.capture_packets.max(2),
+                )
+                .map_err(|err| format!("Fail to open the capture device: {err}"))?,
+            ),
+            // Encodes in its process callback
+            #[cfg(feature = "jack")]
+            SourceSpec::Jack(name) => {
+                return Ok(Box::new(
+                    crate::jack_client::JackCapture::new(
+                        name,
+                        frames_per_packet,
+                        transmitter,
+                        settings,
+                    )
+                    .map_err(|err| format!("Fail to start the JACK client: {err}"))?,
+                ))
+            }
+        };
+        // Captured once in 24 bits, each codec takes what it needs from it
+        if source.format() != SourceFormat::STREAM {
+            return Err(format!("Source must provide {}", SourceFormat::STREAM));
+        }
+        Ok(Box::new(Encoding {
+            source,
+            transmitter,
+            settings,
+            buffer: vec![0; frames_per_packet * SourceFormat::STREAM.bytes_per_frame()],
+        }))
+    }
+}
+
+/// Brings back what a source depends on after the server restarted, like a sink loaded by the
+/// relay.
+type Setup = Box<dyn Fn() -> Result<(), String> + Send>;
+
+/// Follows the source it was opened with: when the default sink changes or devices come and go,
+/// the pattern is resolved again and capture moves to the new source. When the server goes away
+/// it reconnects, backing off until it is back.
+pub struct PulseSource {
+    simple: libpulse_simple_binding::Simple,
+    pattern: Option<String>,
+    name: String,
+    attr: PulseBuffer,
+    devices_changed: Arc<AtomicBool>,
+    setup: Option<Setup>,
+}
+
+impl PulseSource {
+    /// Records the source named `pattern` or whose description contains it, the monitor of the
+    /// default sink without one.
+    pub fn new(pattern: Option<&str>, attr: PulseBuffer) -> Result<PulseSource, String> {
+        let name = crate::utils::pulse_get_source_by_name(pattern)?;
+        info!("Output: {name}");
+        let simple = Self::open(&name, attr)
+            .map_err(|err| format!("Fail to connect to the audio server: {err}"))?;
+        let devices_changed = Arc::new(AtomicBool::new(false));
+        let flag = devices_changed.clone();
+        crate::utils::pulse_watch_devices(move || flag.store(true, Ordering::Relaxed));
+        Ok(PulseSource {
+            simple,
+            pattern: pattern.map(str::to_string),
+            name,
+            attr,
+            devices_changed,
+            setup: None,
+        })
+    }
+
+    /// Runs `setup` before reconnecting.
+    pub fn with_setup(self, setup: impl Fn() -> Result<(), String> + Send + 'static) -> Self {
+        PulseSource {
+            setup: Some(Box::new(setup)),
+            ..self
+        }
+    }
+
+    fn open(
+        name: &str,
+        attr: PulseBuffer,
+    ) -> Result<libpulse_simple_binding::Simple, libpulse_binding::error::PAErr> {
+        use libpulse_binding::{def::BufferAttr, sample, stream::Direction};
+
+        let format = SourceFormat::STREAM;
+        let audio_spec = sample::Spec {
+            format: sample::Format::S24le,
+            rate: format.sample_rate,
+            channels: format.channels,
+        };
+        let attr = BufferAttr {
+            maxlength: attr.maxlength,
+            tlength: attr.tlength,
+            prebuf: attr.prebuf,
+            minreq: attr.minreq,
+            fragsize: attr.fragsize,
+        };
+        libpulse_simple_binding::Simple::new(
+            None,
+            "pc_relay",
+            Direction::Record,
+            Some(name),
+            "System sound",
+            &audio_spec,
+            None,
+            Some(&attr),
+        )
+    }
+
+    /// Moves capture to the source the pattern now resolves to. Keeps the current stream if that
+    /// fails.
+    fn follow(&mut self) {
+        let name = match crate::utils::pulse_get_source_by_name(self.pattern.as_deref()) {
+            Ok(name) => name,
+            Err(err) => {
+                warn!("{err}, staying on {}", self.name);
+                self.name.clone()
+            }
+        };
+        if name == self.name {
+            return;
+        }
+        match Self::open(&name, self.attr) {
+            Ok(simple) => {
+                info!("Output: {name}");
+                self.simple = simple;
+                self.name = name;
+            }
+            Err(err) => error!("Fail to open {name}: {err}"),
+        }
+    }
+
+    /// Opens the source again, until it works.
+    fn reconnect(&mut self) {
+        let (simple, name) = crate::utils::retry("Reconnecting the capture", || {
+            if let Some(setup) = &self.setup {
+                setup()?;
+            }
+            let name = crate::utils::pulse_get_source_by_name(self.pattern.as_deref())?;
+            let simple = Self::open(&name, self.attr)
+                .map_err(|err| format!("Fail to open {name}: {err}"))?;
+            Ok((simple, name))
+        });
+        info!("Output: {name}");
+        self.simple = simple;
+        self.name = name;
+    }
+}
+
+impl AudioSource for PulseSource {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        if self.devices_changed.swap(false, Ordering::Relaxed) {
+            self.follow();
+        }
+        while let Err(err) = self.simple.read(buffer) {
+            // The source or the whole server went away under the stream
+            warn!("Capture failed: {err}");
+            self.reconnect();
+        }
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        self.simple
+            .get_latency()
+            .map_or(Duration::ZERO, |lat| Duration::from_micros(lat.0))
+    }
+
+    fn format(&self) -> SourceFormat {
+        SourceFormat::STREAM
+    }
+}
+
+#[cfg(feature = "pipewire")]
+impl AudioSource for crate::pipewire_stream::PipeWireCapture {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        crate::pipewire_stream::PipeWireCapture::read(self, buffer)
+    }
+
+    fn latency(&self) -> Duration {
+        crate::pipewire_stream::PipeWireCapture::latency(self)
+    }
+
+    fn format(&self) -> SourceFormat {
+        SourceFormat::STREAM
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl AudioSource for crate::alsa_pcm::AlsaCapture {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        crate::alsa_pcm::AlsaCapture::read(self, buffer)
+    }
+
+    fn latency(&self) -> Duration {
+        crate::alsa_pcm::AlsaCapture::latency(self)
+    }
+
+    fn format(&self) -> SourceFormat {
+        SourceFormat::STREAM
+    }
+}
+
+#[cfg(feature = "jack")]
+impl PacketSource for crate::jack_client::JackCapture {
+    fn next(&mut self, packet: &mut Vec<u8>) -> io::Result<Outgoing> {
+        crate::jack_client::JackCapture::next(self, packet)
+    }
+
+    fn latency(&self) -> Duration {
+        crate::jack_client::JackCapture::latency(self)
+    }
+}
+
+/// Sleeps until `frames` frames from `start` are due, sources without a clock of their own are
+/// paced like a sound card.
+fn pace(start: &mut Option<Instant>, frames: usize) {
+    let start = *start.get_or_insert_with(Instant::now);
+    let due = start + SourceFormat::STREAM.duration(frames);
+    std::thread::sleep(due.saturating_duration_since(Instant::now()));
+}
+
+/// Phase of the logarithmic sweep from 20 Hz to 20 kHz at `t` seconds into a `secs` long sweep.
+fn sweep_phase(t: f64, secs: f64) -> f64 {
+    const FROM: f64 = 20.0;
+    const TO: f64 = 20000.0;
+    let rate = (TO / FROM).ln() / secs;
+    2.0 * PI * FROM * ((rate * t).exp() - 1.0) / rate
+}
+
+/// White noise through Paul Kellet's pink filter, about -3 dB per octave.
+fn pink_noise() -> impl FnMut(f64) -> f64 + Send {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut b = [0f64; 7];
+    move |_| {
+        // xorshift64, good enough for noise
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let white = (seed >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[..6].iter().sum::<f64>() + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.11
+    }
+}
+
+/// Loads a file in the stream format. WAV files must be 48 kHz mono or stereo, anything else is
+/// raw 48 kHz stereo, 16 bit if the name ends in `.s16` and 24 bit otherwise.
+fn load_file(path: &Path) -> io::Result<Vec<u8>> {
+    let format = SourceFormat::STREAM;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    let (samples, channels) = match extension {
+        Some("wav") => {
+            let wav = Wav::read(path)?;
+            if wav.spec.sample_rate != format.sample_rate {
+                return Err(invalid(format!(
+                    "{path:?} is {} Hz, resample it to {} Hz",
+                    wav.spec.sample_rate, format.sample_rate
+                )));
+            }
+            if wav.spec.channels > 2 {
+                return Err(invalid(format!("{path:?} has more than 2 channels")));
+            }
+            (wav.samples_i24(), wav.spec.channels as usize)
+        }
+        Some("s16") => {
+            let samples = std::fs::read(path)?
+                .chunks_exact(2)
+                .map(|s| (i16::from_le_bytes([s[0], s[1]]) as i32) << 8)
+                .collect();
+            (samples, 2)
+        }
+        _ => {
+            let mut data = std::fs::read(path)?;
+            data.truncate(data.len() / format.bytes_per_frame() * format.bytes_per_frame());
+            return Ok(data);
+        }
+    };
+    let mut data = Vec::with_capacity(samples.len() / channels * format.bytes_per_frame());
+    for frame in samples.chunks_exact(channels) {
+        for channel in 0..format.channels as usize {
+            let sample = frame[channel.min(channels - 1)];
+            data.extend_from_slice(&sample.to_le_bytes()[..3]);
+        }
+    }
+    Ok(data)
+}
+
+/// Plays files one after the other and starts over after the last one. They are all loaded
+/// when the source opens, so a bad file fails right away rather than mid stream.
+pub struct FileSource {
+    tracks: Vec<(PathBuf, Vec<u8>)>,
+    track: usize,
+    position: usize,
+    frames: usize,
+    start: Option<Instant>,
+}
+
+impl FileSource {
+    pub fn new(paths: &[PathBuf]) -> io::Result<FileSource> {
+        let mut tracks = Vec::with_capacity(paths.len());
+        for path in paths {
+            let data = load_file(path)?;
+            let format = SourceFormat::STREAM;
+            info!(
+                "{path:?}: {:.1} s",
+                format
+                    .duration(data.len() / format.bytes_per_frame())
+                    .as_secs_f64()
+            );
+            tracks.push((path.clone(), data));
+        }
+        if tracks.iter().all(|(_, data)| data.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No audio in the files",
+            ));
+        }
+        Ok(FileSource {
+            tracks,
+            track: 0,
+            position: 0,
+            frames: 0,
+            start: None,
+        })
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let (path, data) = &self.tracks[self.track];
+            let count = (data.len() - self.position).min(buffer.len() - filled);
+            buffer[filled..filled + count]
+                .copy_from_slice(&data[self.position..self.position + count]);
+            filled += count;
+            self.position += count;
+            if self.position == data.len() {
+                self.track = (self.track + 1) % self.tracks.len();
+                self.position = 0;
+                info!("Playing {:?} after {path:?}", self.tracks[self.track].0);
+            }
+        }
+        self.frames += buffer.len() / SourceFormat::STREAM.bytes_per_frame();
+    }
+}
+
+impl AudioSource for FileSource {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        self.fill(buffer);
+        pace(&mut self.start, self.frames);
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn format(&self) -> SourceFormat {
+        SourceFormat::STREAM
+    }
+}
+
+/// Computes every sample from its time in seconds, the same on both channels, and hands them
+/// out no faster than a sound card would.
+pub struct GeneratorSource<F> {
+    generate: F,
+    frame: usize,
+    start: Option<Instant>,
+}
+
+impl<F: FnMut(f64) -> f64 + Send> GeneratorSource<F> {
+    pub fn new(generate: F) -> GeneratorSource<F> {
+        GeneratorSource {
+            generate,
+            frame: 0,
+            start: None,
+        }
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        let format = self.format();
+        for frame in buffer.chunks_exact_mut(format.bytes_per_frame()) {
+            let t = self.frame as f64 / format.sample_rate as f64;
+            let sample = ((self.generate)(t).clamp(-1.0, 1.0) * 8388607.0) as i32;
+            for channel in frame.chunks_exact_mut(3) {
+                channel.copy_from_slice(&sample.to_le_bytes()[..3]);
+            }
+            self.frame += 1;
+        }
+    }
+}
+
+impl<F: FnMut(f64) -> f64 + Send> AudioSource for GeneratorSource<F> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        self.fill(buffer);
+        pace(&mut self.start, self.frame);
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn format(&self) -> SourceFormat {
+        SourceFormat::STREAM
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_spec() {
+        assert_eq!("pulse".parse(), Ok(SourceSpec::Pulse(None)));
+        assert_eq!(
+            "pulse:Monitor of Jabra".parse(),
+            Ok(SourceSpec::Pulse(Some("Monitor of Jabra".to_string())))
+        );
+        assert_eq!("sine:440".parse(), Ok(SourceSpec::Sine(440.0)));
+        assert_eq!("sine".parse(), Ok(SourceSpec::Sine(1000.0)));
+        assert!("sine:loud".parse::<SourceSpec>().is_err());
+        assert!("oss".parse::<SourceSpec>().is_err());
+        assert_eq!("sweep".parse(), Ok(SourceSpec::Sweep(10.0)));
+        assert!("sweep:0".parse::<SourceSpec>().is_err());
+        assert_eq!("click:500".parse(), Ok(SourceSpec::Clicks(500)));
+        assert_eq!(
+            "file:a.wav,/tmp/b.raw".parse(),
+            Ok(SourceSpec::Files(vec!["a.wav".into(), "/tmp/b.raw".into()]))
+        );
+        assert!("file:".parse::<SourceSpec>().is_err());
+        assert_eq!(
+            "app:1234".parse(),
+            Ok(SourceSpec::App(crate::utils::AppMatch::Pid(1234)))
+        );
+        assert_eq!(
+            "app:Spotify".parse(),
+            Ok(SourceSpec::App(crate::utils::AppMatch::Name(
+                "Spotify".to_string()
+            )))
+        );
+        #[cfg(feature = "alsa")]
+        assert_eq!(
+            "alsa:hw:Loopback,1".parse(),
+            Ok(SourceSpec::Alsa("hw:Loopback,1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_generator_source() {
+        let mut source = GeneratorSource::new(|t| if t > 0.0 { 0.5 } else { -1.0 });
+        let mut buffer = [0u8; 2 * 6];
+        source.fill(&mut buffer);
+        assert_eq!(buffer[..6], [0x01, 0x00, 0x80, 0x01, 0x00, 0x80]);
+        assert_eq!(buffer[6..], [0xff, 0xff, 0x3f, 0xff, 0xff, 0x3f]);
+
+        let start = Instant::now();
+        source.read(&mut [0u8; 480 * 6]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_signals() {
+        // The sweep starts at 20 Hz and ends at 20 kHz
+        let freq = |t: f64| (sweep_phase(t + 1e-6, 10.0) - sweep_phase(t, 10.0)) / 1e-6 / 2.0 / PI;
+        assert!((freq(0.0) - 20.0).abs() < 0.1);
+        assert!((freq(10.0) - 20000.0).abs() < 1.0);
+
+        let mut pink = pink_noise();
+        let samples: Vec<f64> = (0..48000).map(|_| pink(0.0)).collect();
+        assert!(samples.iter().all(|sample| sample.abs() < 1.0));
+        let rms = (samples.iter().map(|s| s * s).sum::<f64>() / samples.len() as f64).sqrt();
+        assert!(rms > 0.01);
+    }
+
+    #[test]
+    fn test_file_source() {
+        use crate::wav::WavSpec;
+
+        let path = std::env::temp_dir().join(format!("source-{}.wav", std::process::id()));
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+        };
+        Wav::from_samples_i24(spec, &[256, -256, 512])
+            .write(&path)
+            .unwrap();
+        let mut source = FileSource::new(&[path.clone(), path.clone()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Mono is played on both channels, and the playlist loops
+        let mut buffer = [0u8; 8 * 6];
+        source.fill(&mut buffer);
+        let samples: Vec<i32> = buffer
+            .chunks_exact(3)
+            .map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8)
+            .collect();
+        let frame = |sample| [sample, sample];
+        let expected: Vec<i32> = [256, -256, 512, 256, -256, 512, 256, -256]
+            .into_iter()
+            .flat_map(frame)
+            .collect();
+        assert_eq!(samples, expected);
+
+        let path = std::env::temp_dir().join(format!("source-{}.wav", std::process::id() + 1));
+        let spec = WavSpec {
+            sample_rate: 44100,
+            ..spec
+        };
+        Wav::from_samples_i24(spec, &[0]).write(&path).unwrap();
+        assert!(FileSource::new(std::slice::from_ref(&path)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,726 @@
+//! Both ends of an audio stream: the server side turns captured PCM into packets of the
+//! current codec, the receiver side turns packets back into PCM and follows codec changes.
+
+use crate::{
+    aptx::AptxContext,
+    protocol::{Codec, Control, Volume, SAMPLE_RATE},
+};
+use log::error;
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU16, AtomicU8, Ordering},
+    time::Duration,
+};
+
+/// Converts interleaved 24 bit little endian samples to 16 bit.
+pub fn s24_to_s16(input: &[u8], output: &mut Vec<u8>) {
+    output.clear();
+    for sample in input.chunks_exact(3) {
+        output.extend_from_slice(&sample[1..]);
+    }
+}
+
+/// Converts interleaved 24 bit stereo to 16 bit at half the rate, averaging pairs of frames.
+pub fn downsample_s24_to_s16(input: &[u8], output: &mut Vec<u8>) {
+    output.clear();
+    for frames in input.chunks_exact(12) {
+        for channel in 0..2 {
+            let sample = |frame: usize| {
+                let at = frame * 6 + channel * 3;
+                i32::from_le_bytes([0, frames[at], frames[at + 1], frames[at + 2]]) >> 8
+            };
+            let average = (sample(0) + sample(1)) >> 9;
+            output.extend_from_slice(&(average as i16).to_le_bytes());
+        }
+    }
+}
+
+/// Converts interleaved 16 bit stereo to twice the rate, interpolating between frames. `last`
+/// carries the last frame over to the next call.
+pub fn upsample_s16(input: &[u8], last: &mut [i16; 2], output: &mut Vec<u8>) {
+    output.clear();
+    for frame in input.chunks_exact(4) {
+        let frame = [
+            i16::from_le_bytes([frame[0], frame[1]]),
+            i16::from_le_bytes([frame[2], frame[3]]),
+        ];
+        for channel in 0..2 {
+            let middle = (i32::from(last[channel]) + i32::from(frame[channel])) / 2;
+            output.extend_from_slice(&(middle as i16).to_le_bytes());
+        }
+        for sample in frame {
+            output.extend_from_slice(&sample.to_le_bytes());
+        }
+        *last = frame;
+    }
+}
+
+/// Converts mono 16 bit little endian samples to interleaved 24 bit stereo, the encoder input.
+pub fn s16_mono_to_s24_stereo(input: &[u8], output: &mut Vec<u8>) {
+    output.clear();
+    for sample in input.chunks_exact(2) {
+        for _ in 0..2 {
+            output.extend_from_slice(&[0, sample[0], sample[1]]);
+        }
+    }
+}
+
+/// Scales interleaved 24 bit samples in place, with the cubic curve PulseAudio uses for its
+/// software volume so the steps sound even.
+pub fn apply_volume(samples: &mut [u8], volume: Volume) {
+    if volume == Volume::default() {
+        return;
+    }
+    let gain = if volume.muted {
+        0.0
+    } else {
+        (volume.percent as f64 / Volume::MAX as f64).powi(3)
+    };
+    for sample in samples.chunks_exact_mut(3) {
+        let value = i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8;
+        let scaled = (value as f64 * gain).round() as i32;
+        sample.copy_from_slice(&scaled.to_le_bytes()[..3]);
+    }
+}
+
+fn frames_duration(frames: usize) -> Duration {
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
+/// Where the latency of a stream goes, from the capture on the server to the playback on the
+/// receiver.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyBudget {
+    /// Audio buffered by the capture before the server reads it.
+    pub capture: Duration,
+    pub codec: Duration,
+    /// Waiting for a whole packet of frames before sending it.
+    pub packetization: Duration,
+    /// Audio queued on the receiver before it is played.
+    pub jitter_buffer: Duration,
+    /// One way network delay, half the measured round trip, zero when unknown.
+    pub network: Duration,
+    /// Delay of the receiver's output after its buffer, zero when unknown.
+    pub playback: Duration,
+}
+
+impl LatencyBudget {
+    pub fn new(
+        codec: Codec,
+        frames_per_packet: usize,
+        capture_frames: usize,
+        jitter_buffer: Duration,
+    ) -> LatencyBudget {
+        LatencyBudget {
+            capture: frames_duration(capture_frames),
+            codec: frames_duration(codec.delay_frames()),
+            packetization: frames_duration(frames_per_packet),
+            jitter_buffer,
+            network: Duration::ZERO,
+            playback: Duration::ZERO,
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.capture
+            + self.codec
+            + self.packetization
+            + self.network
+            + self.jitter_buffer
+            + self.playback
+    }
+}
+
+impl Display for LatencyBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{:.1} ms (capture {:.1} + codec {:.1} + packet {:.1}",
+            ms(self.total()),
+            ms(self.capture),
+            ms(self.codec),
+            ms(self.packetization),
+        )?;
+        // Only known from the receiver's feedback
+        if !self.network.is_zero() {
+            write!(f, " + network {:.1}", ms(self.network))?;
+        }
+        write!(f, " + jitter buffer {:.1}", ms(self.jitter_buffer))?;
+        if !self.playback.is_zero() {
+            write!(f, " + playback {:.1}", ms(self.playback))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Tells when the captured audio has been quiet long enough to stop sending it.
+pub struct SilenceDetector {
+    threshold: i32,
+    hangover: usize,
+    quiet: usize,
+}
+
+impl SilenceDetector {
+    /// Packets peaking below `threshold_db` dBFS are quiet, the stream is silent after
+    /// `hangover` of them in a row, which also lets the codec flush what it still holds.
+    pub fn new(threshold_db: f64, hangover: usize) -> SilenceDetector {
+        SilenceDetector {
+            threshold: (f64::from(1 << 23) * 10f64.powf(threshold_db / 20.0)) as i32,
+            hangover,
+            quiet: 0,
+        }
+    }
+
+    /// Feeds one packet of interleaved 24 bit samples, returns whether the stream is silent.
+    pub fn update(&mut self, input: &[u8]) -> bool {
+        let loud = input.chunks_exact(3).any(|sample| {
+            let sample = i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8;
+            sample.abs() > self.threshold
+        });
+        self.quiet = if loud { 0 } else { self.quiet + 1 };
+        self.quiet > self.hangover
+    }
+}
+
+pub struct StreamEncoder {
+    codec: Codec,
+    ctx: Box<AptxContext>,
+    pcm: Vec<u8>,
+}
+
+impl StreamEncoder {
+    pub fn new(codec: Codec) -> StreamEncoder {
+        Self::with_frames(codec, 0)
+    }
+
+    /// Encoder with its buffer sized for packets of `frames` frames, encoding them never
+    /// allocates.
+    pub fn with_frames(codec: Codec, frames: usize) -> StreamEncoder {
+        StreamEncoder {
+            codec,
+            ctx: AptxContext::new(codec.hd()),
+            pcm: Vec::with_capacity(frames * 4),
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Starts a fresh stream with `codec`, returning the control packet announcing it.
+    pub fn switch(&mut self, codec: Codec) -> Control {
+        self.codec = codec;
+        *self.ctx = AptxContext::init(codec.hd());
+        Control::Codec(codec)
+    }
+
+    /// Restarts the codec for audio resuming after silence, returning the control packet that
+    /// restarts the receiver's decoder as well.
+    pub fn resume(&mut self) -> Control {
+        *self.ctx = AptxContext::init(self.codec.hd());
+        Control::Resume(self.codec)
+    }
+
+    /// Encodes interleaved stereo 24 bit samples, a multiple of [`Codec::block_frames`], into
+    /// `packet`.
+    pub fn encode(&mut self, input: &[u8], packet: &mut Vec<u8>) {
+        let pcm = match self.codec {
+            Codec::AptxHd => input,
+            Codec::AptxLow => {
+                downsample_s24_to_s16(input, &mut self.pcm);
+                &self.pcm
+            }
+            Codec::Pcm | Codec::Aptx => {
+                s24_to_s16(input, &mut self.pcm);
+                &self.pcm
+            }
+        };
+        if !self.codec.is_aptx() {
+            packet.clear();
+            packet.extend_from_slice(pcm);
+            return;
+        }
+        packet.resize(self.codec.packet_size(input.len() / 6), 0);
+        let mut written = 0;
+        let processed = self.ctx.encode(pcm, packet, &mut written);
+        if processed != pcm.len() {
+            error!(
+                "Fail to encode processed {} out of {}",
+                processed,
+                pcm.len()
+            );
+        }
+        packet.truncate(written);
+    }
+}
+
+/// Codec and volume the server streams with, changed from its control threads and read for
+/// every packet by whichever thread encodes, which can be an audio callback.
+pub struct StreamSettings {
+    codec: AtomicU8,
+    /// [`Volume::percent`], plus 256 when muted
+    volume: AtomicU16,
+}
+
+impl StreamSettings {
+    pub fn new(codec: Codec) -> StreamSettings {
+        StreamSettings {
+            codec: AtomicU8::new(codec.id()),
+            volume: AtomicU16::new(Self::pack(Volume::default())),
+        }
+    }
+
+    fn pack(volume: Volume) -> u16 {
+        u16::from(volume.percent) | u16::from(volume.muted) << 8
+    }
+
+    pub fn codec(&self) -> Codec {
+        Codec::from_id(self.codec.load(Ordering::Relaxed)).unwrap_or(Codec::Pcm)
+    }
+
+    pub fn set_codec(&self, codec: Codec) {
+        self.codec.store(codec.id(), Ordering::Relaxed);
+    }
+
+    pub fn volume(&self) -> Volume {
+        let packed = self.volume.load(Ordering::Relaxed);
+        Volume {
+            percent: packed as u8,
+            muted: packed >> 8 != 0,
+        }
+    }
+
+    pub fn set_volume(&self, volume: Volume) {
+        self.volume.store(Self::pack(volume), Ordering::Relaxed);
+    }
+
+    /// Changes the volume with `change` atomically, returns the new one.
+    pub fn update_volume(&self, change: impl Fn(Volume) -> Volume) -> Volume {
+        let mut current = self.volume();
+        loop {
+            let next = change(current);
+            match self.volume.compare_exchange(
+                Self::pack(current),
+                Self::pack(next),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return next,
+                Err(packed) => {
+                    current = Volume {
+                        percent: packed as u8,
+                        muted: packed >> 8 != 0,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What became of one packet of captured audio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outgoing {
+    /// Encoded audio, after the marker to send first when the encoder restarted.
+    Audio {
+        marker: Option<Control>,
+        /// Silent packets skipped right before this one
+        resumed_after: usize,
+    },
+    /// Quiet audio not sent, the `packets`th in a row counting from 0.
+    Silence { packets: usize },
+}
+
+/// The server's side of a stream, one packet of captured audio at a time: volume, silence
+/// detection and encoding with the codec of the [`StreamSettings`]. Nothing is allocated past
+/// construction, so it can run in an audio callback.
+pub struct Transmitter {
+    encoder: StreamEncoder,
+    detector: Option<SilenceDetector>,
+    scale_volume: bool,
+    silent_packets: usize,
+}
+
+impl Transmitter {
+    /// Transmitter of packets of `frames` frames. Without a `detector` every packet is sent,
+    /// without `scale_volume` the volume is left to whoever plays the captured audio.
+    pub fn new(
+        codec: Codec,
+        frames: usize,
+        detector: Option<SilenceDetector>,
+        scale_volume: bool,
+    ) -> Transmitter {
+        Transmitter {
+            encoder: StreamEncoder::with_frames(codec, frames),
+            detector,
+            scale_volume,
+            silent_packets: 0,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.encoder.codec()
+    }
+
+    /// Processes one packet of interleaved stereo 24 bit samples, scaled in place. The encoded
+    /// audio is left in `packet`.
+    pub fn process(
+        &mut self,
+        pcm: &mut [u8],
+        settings: &StreamSettings,
+        packet: &mut Vec<u8>,
+    ) -> Outgoing {
+        if self.scale_volume {
+            apply_volume(pcm, settings.volume());
+        }
+        if let Some(detector) = &mut self.detector {
+            if detector.update(pcm) {
+                self.silent_packets += 1;
+                return Outgoing::Silence {
+                    packets: self.silent_packets - 1,
+                };
+            }
+        }
+        let resumed_after = std::mem::take(&mut self.silent_packets);
+        let codec = settings.codec();
+        // Switches and restarts after silence both start a fresh encoder
+        let marker = if codec != self.encoder.codec() {
+            Some(self.encoder.switch(codec))
+        } else if resumed_after > 0 {
+            Some(self.encoder.resume())
+        } else {
+            None
+        };
+        self.encoder.encode(pcm, packet);
+        Outgoing::Audio {
+            marker,
+            resumed_after,
+        }
+    }
+}
+
+pub enum Packet<'a> {
+    /// Decoded interleaved stereo PCM, 16 bit or 24 bit depending on the codec.
+    Audio(&'a [u8]),
+    /// Zeros to play as the stream goes silent, empty for the keep-alives that follow.
+    Silence(&'a [u8]),
+    Control(Control),
+}
+
+pub struct StreamDecoder {
+    codec: Codec,
+    ctx: Box<AptxContext>,
+    pcm: Vec<u8>,
+    /// Output of [`Codec::AptxLow`] brought back to the stream's rate
+    resampled: Vec<u8>,
+    last_frame: [i16; 2],
+    silent: bool,
+}
+
+impl StreamDecoder {
+    pub fn new(codec: Codec) -> StreamDecoder {
+        Self::with_frames(codec, 0)
+    }
+
+    /// Decoder with its buffers sized for packets of `frames` frames, decoding them never
+    /// allocates.
+    pub fn with_frames(codec: Codec, frames: usize) -> StreamDecoder {
+        StreamDecoder {
+            codec,
+            ctx: AptxContext::new(codec.hd()),
+            // A codeword more than the packet may come out of the decoder's synchronization
+            pcm: Vec::with_capacity(frames * 6 + 24),
+            resampled: Vec::with_capacity(frames * 4 + 16),
+            last_frame: [0; 2],
+            silent: false,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn switch(&mut self, codec: Codec) {
+        if codec != self.codec {
+            self.restart(codec);
+        }
+    }
+
+    fn restart(&mut self, codec: Codec) {
+        self.ctx.decode_sync_finish();
+        self.codec = codec;
+        *self.ctx = AptxContext::init(codec.hd());
+        self.last_frame = [0; 2];
+    }
+
+    /// Handles one packet received on the audio port. Control packets are applied before
+    /// being handed back, so the caller only has to reconfigure its own output.
+    pub fn process<'a>(&'a mut self, packet: &'a [u8]) -> Packet<'a> {
+        match Control::parse(packet) {
+            Some(Control::Silence { .. }) if self.silent => return Packet::Silence(&[]),
+            Some(Control::Silence { frames }) => {
+                // The server restarts its encoder when audio comes back
+                self.silent = true;
+                self.restart(self.codec);
+                self.pcm.clear();
+                self.pcm
+                    .resize(frames as usize * 2 * self.codec.bytes_per_sample(), 0);
+                return Packet::Silence(&self.pcm);
+            }
+            Some(control @ Control::Codec(codec)) => {
+                self.switch(codec);
+                return Packet::Control(control);
+            }
+            Some(control @ Control::Resume(codec)) => {
+                self.restart(codec);
+                return Packet::Control(control);
+            }
+            Some(control) => return Packet::Control(control),
+            None => self.silent = false,
+        }
+        if !self.codec.is_aptx() {
+            return Packet::Audio(packet);
+        }
+        let sample_size = if self.codec.hd() { 6 } else { 4 };
+        self.pcm.resize(
+            (packet.len() / sample_size + 1) * 4 * 2 * self.codec.bytes_per_sample(),
+            0,
+        );
+        let (mut written, mut synced, mut dropped) = (0, false, 0);
+        let processed = self.ctx.decode_sync(
+            packet,
+            &mut self.pcm,
+            &mut written,
+            &mut synced,
+            &mut dropped,
+        );
+        if !synced || dropped > 0 {
+            error!("aptX decoding failed, synchronizing {written} {synced} {dropped}");
+        }
+        if processed != packet.len() {
+            error!("aptX decoding failed {processed} != {}", packet.len());
+        }
+        if self.codec == Codec::AptxLow {
+            upsample_s16(
+                &self.pcm[..written],
+                &mut self.last_frame,
+                &mut self.resampled,
+            );
+            return Packet::Audio(&self.resampled);
+        }
+        Packet::Audio(&self.pcm[..written])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::VolumeCommand;
+
+    #[test]
+    fn test_s24_to_s16() {
+        let mut output = Vec::new();
+        s24_to_s16(&[0x11, 0x22, 0x33, 0xff, 0xff, 0xff], &mut output);
+        assert_eq!(output, [0x22, 0x33, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_s16_mono_to_s24_stereo() {
+        let mut output = Vec::new();
+        s16_mono_to_s24_stereo(&[0x22, 0x33, 0xff], &mut output);
+        assert_eq!(output, [0, 0x22, 0x33, 0, 0x22, 0x33]);
+    }
+
+    #[test]
+    fn test_resampling() {
+        let mut half = Vec::new();
+        downsample_s24_to_s16(&[0, 0, 0x10, 0, 0, 0xf0, 0, 0, 0x30, 0, 0, 0xd0], &mut half);
+        assert_eq!(half, [0, 0x20, 0, 0xe0]);
+        let (mut last, mut output) = ([0; 2], Vec::new());
+        upsample_s16(&half, &mut last, &mut output);
+        assert_eq!(output, [0, 0x10, 0, 0xf0, 0, 0x20, 0, 0xe0]);
+        assert_eq!(last, [0x2000, -0x2000]);
+    }
+
+    #[test]
+    fn test_stream_switch() {
+        let mut encoder = StreamEncoder::new(Codec::Pcm);
+        let mut decoder = StreamDecoder::new(Codec::Pcm);
+        let input = vec![0u8; 512 * 6];
+        let mut packet = Vec::new();
+
+        encoder.encode(&input, &mut packet);
+        assert_eq!(packet.len(), Codec::Pcm.packet_size(512));
+        assert!(matches!(decoder.process(&packet), Packet::Audio(pcm) if pcm.len() == 2048));
+
+        for codec in [Codec::AptxHd, Codec::Aptx, Codec::AptxLow] {
+            let marker = encoder.switch(codec).to_bytes();
+            assert!(matches!(
+                decoder.process(&marker),
+                Packet::Control(Control::Codec(c)) if c == codec
+            ));
+            assert_eq!(decoder.codec(), codec);
+            encoder.encode(&input, &mut packet);
+            assert_eq!(packet.len(), codec.packet_size(512));
+            assert!(matches!(decoder.process(&packet), Packet::Audio(_)));
+        }
+    }
+
+    #[test]
+    fn test_apply_volume() {
+        let mut samples = [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0];
+        apply_volume(&mut samples, Volume::default());
+        assert_eq!(samples, [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]);
+        apply_volume(
+            &mut samples,
+            Volume {
+                percent: 50,
+                muted: false,
+            },
+        );
+        assert_eq!(samples, [0x00, 0x00, 0x08, 0x00, 0x00, 0xf8]);
+        apply_volume(
+            &mut samples,
+            Volume {
+                percent: 100,
+                muted: true,
+            },
+        );
+        assert_eq!(samples, [0; 6]);
+    }
+
+    #[test]
+    fn test_latency_budget() {
+        let budget = LatencyBudget::new(Codec::Aptx, 480, 960, Duration::from_millis(20));
+        assert_eq!(budget.packetization, Duration::from_millis(10));
+        assert_eq!(budget.capture, Duration::from_millis(20));
+        assert_eq!(budget.codec, Duration::from_micros(1875));
+        assert_eq!(budget.total(), Duration::from_micros(51875));
+        let budget = LatencyBudget::new(Codec::Pcm, 480, 960, Duration::ZERO);
+        assert_eq!(budget.codec, Duration::ZERO);
+        assert_eq!(
+            budget.to_string(),
+            "30.0 ms (capture 20.0 + codec 0.0 + packet 10.0 + jitter buffer 0.0)"
+        );
+        let budget = LatencyBudget {
+            network: Duration::from_millis(3),
+            playback: Duration::from_millis(12),
+            ..budget
+        };
+        assert_eq!(
+            budget.to_string(),
+            "45.0 ms (capture 20.0 + codec 0.0 + packet 10.0 + network 3.0 + jitter buffer 0.0 \
+             + playback 12.0)"
+        );
+    }
+
+    #[test]
+    fn test_silence_detector() {
+        let mut detector = SilenceDetector::new(-80.0, 2);
+        let quiet = [0x10, 0, 0].repeat(512 * 2);
+        let loud = [0, 0, 0x10].repeat(512 * 2);
+        assert!(!detector.update(&quiet));
+        assert!(!detector.update(&quiet));
+        assert!(detector.update(&quiet));
+        assert!(!detector.update(&loud));
+    }
+
+    #[test]
+    fn test_stream_silence() {
+        let mut encoder = StreamEncoder::new(Codec::AptxHd);
+        let mut decoder = StreamDecoder::new(Codec::AptxHd);
+        let input = vec![0u8; 512 * 6];
+        let mut packet = Vec::new();
+        let silence = Control::Silence { frames: 512 }.to_bytes();
+
+        encoder.encode(&input, &mut packet);
+        assert!(matches!(decoder.process(&packet), Packet::Audio(_)));
+        assert!(matches!(decoder.process(&silence), Packet::Silence(pcm) if pcm.len() == 512 * 6));
+        assert!(matches!(decoder.process(&silence), Packet::Silence([])));
+
+        let resume = encoder.resume().to_bytes();
+        assert!(matches!(decoder.process(&resume), Packet::Control(_)));
+        encoder.encode(&input, &mut packet);
+        assert!(matches!(decoder.process(&packet), Packet::Audio(_)));
+        assert!(matches!(decoder.process(&silence), Packet::Silence(pcm) if !pcm.is_empty()));
+    }
+
+    #[test]
+    fn test_resume_without_keepalive() {
+        let tone: Vec<u8> = (0..512 * 2)
+            .flat_map(|n| {
+                let sample = (f64::sin(n as f64 / 7.0) * f64::from(1 << 22)) as i32;
+                sample.to_le_bytes()[..3].to_vec()
+            })
+            .collect();
+        let mut encoder = StreamEncoder::new(Codec::Aptx);
+        let mut decoder = StreamDecoder::new(Codec::Aptx);
+        let mut packet = Vec::new();
+        for _ in 0..4 {
+            encoder.encode(&tone, &mut packet);
+            decoder.process(&packet);
+        }
+
+        // The only keep-alive of a short silence is lost, the resume marker still restarts the
+        // decoder along with the encoder
+        let resume = encoder.resume().to_bytes();
+        assert!(matches!(
+            decoder.process(&resume),
+            Packet::Control(Control::Resume(Codec::Aptx))
+        ));
+        encoder.encode(&tone, &mut packet);
+        let Packet::Audio(resumed) = decoder.process(&packet) else {
+            panic!("Not audio");
+        };
+        let mut fresh = StreamDecoder::new(Codec::Aptx);
+        let Packet::Audio(expected) = fresh.process(&packet) else {
+            panic!("Not audio");
+        };
+        assert_eq!(resumed, expected);
+    }
+
+    #[test]
+    fn test_transmitter() {
+        let settings = StreamSettings::new(Codec::Aptx);
+        let detector = SilenceDetector::new(-80.0, 0);
+        let mut transmitter = Transmitter::new(Codec::Aptx, 512, Some(detector), true);
+        let mut loud = [0, 0, 0x40].repeat(512 * 2);
+        let mut packet = Vec::new();
+        let audio = |marker, resumed_after| Outgoing::Audio {
+            marker,
+            resumed_after,
+        };
+
+        assert_eq!(
+            transmitter.process(&mut loud.clone(), &settings, &mut packet),
+            audio(None, 0)
+        );
+        assert_eq!(packet.len(), Codec::Aptx.packet_size(512));
+        for packets in 0..2 {
+            let mut quiet = vec![0; 512 * 6];
+            let outgoing = transmitter.process(&mut quiet, &settings, &mut packet);
+            assert_eq!(outgoing, Outgoing::Silence { packets });
+        }
+        assert_eq!(
+            transmitter.process(&mut loud.clone(), &settings, &mut packet),
+            audio(Some(Control::Resume(Codec::Aptx)), 2)
+        );
+
+        settings.set_codec(Codec::AptxHd);
+        settings.set_volume(Volume {
+            percent: 100,
+            muted: true,
+        });
+        // Muted audio is quiet, the switch waits for it to be loud again
+        let outgoing = transmitter.process(&mut loud, &settings, &mut packet);
+        assert_eq!(outgoing, Outgoing::Silence { packets: 0 });
+        settings.update_volume(|volume| volume.apply(VolumeCommand::Unmute));
+        assert_eq!(settings.volume(), Volume::default());
+        let mut loud = [0, 0, 0x40].repeat(512 * 2);
+        assert_eq!(
+            transmitter.process(&mut loud, &settings, &mut packet),
+            audio(Some(Control::Codec(Codec::AptxHd)), 1)
+        );
+        assert_eq!(transmitter.codec(), Codec::AptxHd);
+    }
+}
@@ -0,0 +1,247 @@
+#[allow(dead_code)]
+mod wav;
+
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Encode a WAV file into a raw aptX / aptX HD stream
+    Encode {
+        input: std::path::PathBuf,
+        output: std::path::PathBuf,
+        /// Force aptX HD, by default it is used for WAV files deeper than 16 bits
+        #[arg(long, conflicts_with = "no_hd")]
+        hd: bool,
+        /// Force aptX even for 24 bit WAV files
+        #[arg(long)]
+        no_hd: bool,
+    },
+    /// Decode a raw aptX / aptX HD stream into a WAV file
+    Decode {
+        input: std::path::PathBuf,
+        output: std::path::PathBuf,
+        /// Input is aptX HD, implied by the .aptxhd extension
+        #[arg(long)]
+        hd: bool,
+        /// Sample rate to write in the WAV header, raw streams do not carry it
+        #[arg(short, long, default_value_t = 48000)]
+        rate: u32,
+    },
+}
+
+/// Offline aptX / aptX HD transcoding, raw streams match ffmpeg's aptx and aptx_hd formats
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, clap::Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[cfg(not(target_os = "android"))]
+fn encode(input: &std::path::Path, output: &std::path::Path, hd: Option<bool>) {
+    use aptx_rust::aptx::AptxContext;
+    use log::{info, warn};
+    use wav::Wav;
+
+    let wav = Wav::read(input).unwrap_or_else(|err| panic!("Fail to read {input:?}: {err}"));
+    let hd = hd.unwrap_or(wav.spec.bits_per_sample > 16);
+    info!(
+        "{input:?}: {} Hz, {} channels, {} bits -> {}",
+        wav.spec.sample_rate,
+        wav.spec.channels,
+        wav.spec.bits_per_sample,
+        if hd { "aptX HD" } else { "aptX" }
+    );
+    if wav.spec.channels > 2 {
+        warn!("Only the first two channels are encoded");
+    }
+    let channels = wav.spec.channels as usize;
+    let mut pcm = Vec::with_capacity(wav.frames() * if hd { 6 } else { 4 });
+    for frame in wav.samples_i24().chunks_exact(channels) {
+        let (left, right) = (frame[0], frame[channels.min(2) - 1]);
+        for sample in [left, right] {
+            if hd {
+                pcm.extend_from_slice(&sample.to_le_bytes()[..3]);
+            } else {
+                pcm.extend_from_slice(&((sample >> 8) as i16).to_le_bytes());
+            }
+        }
+    }
+    // The encoder only takes whole codewords of 4 frames, pad the last one with silence
+    let block = if hd { 24 } else { 16 };
+    pcm.resize(pcm.len().div_ceil(block) * block, 0);
+
+    let sample_size = if hd { 6 } else { 4 };
+    let mut ctx = AptxContext::new(hd);
+    let mut encoded = vec![0; pcm.len() / block * sample_size];
+    let mut written = 0;
+    let processed = ctx.encode(&pcm, &mut encoded, &mut written);
+    assert_eq!(processed, pcm.len(), "Fail to encode the whole input");
+
+    let mut tail = vec![0; AptxContext::LATENCY_SAMPLES.div_ceil(4) * sample_size];
+    let mut tail_written = 0;
+    while ctx.encode_finish(&mut tail, &mut tail_written) == 0 {
+        encoded.extend_from_slice(&tail[..tail_written]);
+    }
+    encoded.extend_from_slice(&tail[..tail_written]);
+
+    std::fs::write(output, &encoded)
+        .unwrap_or_else(|err| panic!("Fail to write {output:?}: {err}"));
+    info!("Wrote {} bytes to {output:?}", encoded.len());
+}
+
+#[cfg(not(target_os = "android"))]
+fn decode(input: &std::path::Path, output: &std::path::Path, hd: bool, rate: u32) {
+    use aptx_rust::aptx::AptxContext;
+    use log::{info, warn};
+    use wav::{Wav, WavSpec};
+
+    let hd = hd || input.extension().is_some_and(|ext| ext == "aptxhd");
+    let encoded =
+        std::fs::read(input).unwrap_or_else(|err| panic!("Fail to read {input:?}: {err}"));
+    info!("{input:?}: {}", if hd { "aptX HD" } else { "aptX" });
+
+    let sample_size = if hd { 6 } else { 4 };
+    let mut ctx = AptxContext::new(hd);
+    let mut pcm = vec![0; (encoded.len() / sample_size + 1) * sample_size * 4];
+    let (mut written, mut synced, mut dropped) = (0, false, 0);
+    ctx.decode_sync(&encoded, &mut pcm, &mut written, &mut synced, &mut dropped);
+    pcm.truncate(written);
+    let dropped = dropped + ctx.decode_sync_finish();
+    if !synced || dropped > 0 {
+        warn!("Stream not in sync, dropped {dropped} bytes");
+    }
+
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: rate,
+        bits_per_sample: if hd { 24 } else { 16 },
+    };
+    Wav { spec, data: pcm }
+        .write(output)
+        .unwrap_or_else(|err| panic!("Fail to write {output:?}: {err}"));
+    info!(
+        "Wrote {} frames to {output:?}",
+        written / spec.bytes_per_frame()
+    );
+}
+
+#[cfg(not(target_os = "android"))]
+fn main() {
+    use clap::Parser;
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    match args.command {
+        Command::Encode {
+            input,
+            output,
+            hd,
+            no_hd,
+        } => encode(&input, &output, (hd || no_hd).then_some(hd)),
+        Command::Decode {
+            input,
+            output,
+            hd,
+            rate,
+        } => decode(&input, &output, hd, rate),
+    }
+}
+
+#[cfg(target_os = "android")]
+fn main() {}
+
+#[cfg(all(test, not(target_os = "android")))]
+mod tests {
+    use super::*;
+    use aptx_rust::aptx::AptxContext;
+    use std::path::PathBuf;
+    use wav::{Wav, WavSpec};
+
+    const FRAMES: usize = 4800;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("aptx-tool-{}-{name}", std::process::id()))
+    }
+
+    /// 1 kHz sine in both channels at half scale, as 24 bit samples.
+    fn sine() -> Vec<i32> {
+        (0..FRAMES)
+            .flat_map(|i| {
+                let phase = 2.0 * std::f64::consts::PI * 1000.0 * i as f64 / 48000.0;
+                let sample = (phase.sin() * (1 << 22) as f64) as i32;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    /// Signal to noise ratio in dB of `decoded` against `original`, at the codec's delay.
+    fn snr(original: &[i32], decoded: &[i32]) -> f64 {
+        let delay = 2 * AptxContext::LATENCY_SAMPLES;
+        (0..=delay)
+            .map(|lag| {
+                let (mut signal, mut noise) = (0.0, 0.0);
+                for (a, b) in original.iter().zip(&decoded[2 * lag..]) {
+                    signal += (*a as f64).powi(2);
+                    noise += (*a as f64 - *b as f64).powi(2);
+                }
+                10.0 * (signal / noise.max(1.0)).log10()
+            })
+            .fold(f64::MIN, f64::max)
+    }
+
+    /// Encodes the sine written with `bits`, decodes it back and returns the encoded size and
+    /// the decoded WAV.
+    fn roundtrip(bits: u16, hd: Option<bool>, extension: &str) -> (usize, Wav) {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: bits,
+        };
+        let (input, encoded, output) = (
+            temp(&format!("{bits}-{extension}.wav")),
+            temp(&format!("{bits}.{extension}")),
+            temp(&format!("{bits}-{extension}-decoded.wav")),
+        );
+        Wav::from_samples_i24(spec, &sine()).write(&input).unwrap();
+        encode(&input, &encoded, hd);
+        decode(&encoded, &output, false, 48000);
+        let size = std::fs::metadata(&encoded).unwrap().len() as usize;
+        let decoded = Wav::read(&output).unwrap();
+        for path in [input, encoded, output] {
+            std::fs::remove_file(path).unwrap();
+        }
+        (size, decoded)
+    }
+
+    #[test]
+    fn test_roundtrip_aptx() {
+        let (size, decoded) = roundtrip(16, None, "aptx");
+        // Every 4 frames are a 4 byte codeword, the flush adds the codec's delay
+        let flushed = AptxContext::LATENCY_SAMPLES.div_ceil(4) * 4;
+        assert_eq!(size, FRAMES + flushed);
+        assert_eq!(decoded.spec.bits_per_sample, 16);
+        assert!(decoded.frames() >= FRAMES);
+        let snr = snr(&sine(), &decoded.samples_i24());
+        assert!(snr > 30.0, "{snr} dB");
+    }
+
+    #[test]
+    fn test_roundtrip_aptx_hd() {
+        let (size, decoded) = roundtrip(24, None, "aptxhd");
+        let flushed = AptxContext::LATENCY_SAMPLES.div_ceil(4) * 4;
+        assert_eq!(size, (FRAMES + flushed) / 4 * 6);
+        assert_eq!(decoded.spec.bits_per_sample, 24);
+        assert!(decoded.frames() >= FRAMES);
+        let snr = snr(&sine(), &decoded.samples_i24());
+        assert!(snr > 30.0, "{snr} dB");
+    }
+
+    #[test]
+    fn test_hd_from_bit_depth() {
+        // Deeper than 16 bits picks aptX HD unless told otherwise, and the other way around
+        let hd_size = (FRAMES + AptxContext::LATENCY_SAMPLES.div_ceil(4) * 4) / 4 * 6;
+        assert_eq!(roundtrip(24, None, "hd.aptxhd").0, hd_size);
+        assert_eq!(roundtrip(24, Some(false), "aptx").0, hd_size / 6 * 4);
+        assert_eq!(roundtrip(16, Some(true), "forced.aptxhd").0, hd_size);
+    }
+}
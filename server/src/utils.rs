@@ -1,14 +1,16 @@
 extern crate libpulse_binding as pulse;
 extern crate libpulse_simple_binding as psimple;
 
-use log::{debug, error, info};
+use aptx_rust::protocol::{Control, Volume, CONTROL_MAX_LEN};
+use log::{debug, error, info, warn};
 
 use std::{
     borrow::Cow,
     fmt::Display,
     net::{SocketAddr, UdpSocket},
+    os::unix::net::UnixDatagram,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use dbus::{
@@ -22,36 +24,821 @@ use pulse::{
     mainloop::standard::Mainloop,
 };
 
-pub fn pulse_get_source_by_name(pattern: &'static str) -> String {
-    let mut main_loop = Mainloop::new().expect("Fail to create pulse mainloop");
-    let mut ctx = Context::new(&main_loop, "test").expect("Fail to get pulse context");
-    ctx.set_state_callback(Some(Box::new(|| {})));
+/// A PulseAudio source, as printed by `list-sources`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PulseSourceInfo {
+    pub name: String,
+    pub description: String,
+    /// Sink this source is the monitor of
+    pub monitor_of: Option<String>,
+}
+
+/// A PulseAudio sink, as printed by `listen list-sinks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PulseSinkInfo {
+    pub name: String,
+    pub description: String,
+}
+
+/// Connects to the PulseAudio server, fails rather than waiting when it is not running.
+fn pulse_connect() -> Result<(Mainloop, Context), String> {
+    use pulse::context::State;
+
+    let mut main_loop = Mainloop::new().ok_or("Fail to create pulse mainloop")?;
+    let mut ctx = Context::new(&main_loop, "pc_relay").ok_or("Fail to get pulse context")?;
     ctx.connect(None, FlagSet::NOFLAGS, None)
-        .map_err(|err| error!("{err}"))
-        .unwrap();
+        .map_err(|err| format!("Fail to connect to PulseAudio: {err}"))?;
     loop {
         match ctx.get_state() {
-            pulse::context::State::Ready => {
-                break;
+            State::Ready => return Ok((main_loop, ctx)),
+            State::Failed | State::Terminated => {
+                return Err(format!("Fail to connect to PulseAudio: {}", ctx.errno()))
             }
-            _ => main_loop.iterate(false),
-        };
+            _ => {
+                if let pulse::mainloop::standard::IterateResult::Err(err) = main_loop.iterate(true)
+                {
+                    return Err(format!("Fail to connect to PulseAudio: {err}"));
+                }
+            }
+        }
+    }
+}
+
+/// Runs one blocking iteration of the main loop, fails once the connection is gone. A context
+/// that failed or was terminated leaves `iterate` blocking forever rather than failing, so its
+/// state is checked before and after.
+fn pulse_iterate(main_loop: &mut Mainloop, ctx: &Context) -> Result<(), String> {
+    use pulse::context::State;
+
+    let check = |ctx: &Context| match ctx.get_state() {
+        State::Failed | State::Terminated => Err(format!("{}", ctx.errno())),
+        _ => Ok(()),
+    };
+    check(ctx)?;
+    if let pulse::mainloop::standard::IterateResult::Err(err) = main_loop.iterate(true) {
+        return Err(format!("{err}"));
+    }
+    check(ctx)
+}
+
+/// Runs the main loop until `op` is done, or cancelled by the connection going away.
+fn pulse_wait<T: ?Sized>(
+    main_loop: &mut Mainloop,
+    ctx: &Context,
+    op: pulse::operation::Operation<T>,
+) {
+    while let pulse::operation::State::Running = op.get_state() {
+        if pulse_iterate(main_loop, ctx).is_err() {
+            break;
+        }
+    }
+}
+
+/// Delays between attempts to reach a device or server that went away, doubling up to a cap.
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const FIRST: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(5);
+
+    pub fn new() -> Backoff {
+        Backoff { delay: Self::FIRST }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(Self::MAX);
+        delay
     }
-    let name = Arc::new(Mutex::new(String::new()));
-    let name_clone = name.clone();
+
+    pub fn reset(&mut self) {
+        self.delay = Self::FIRST;
+    }
+
+    /// Starts over from the first delay after a connection made at `connected` held for longer
+    /// than the longest delay, so only connections failing right away slow down.
+    pub fn reset_if_held(&mut self, connected: Instant) {
+        if connected.elapsed() > Self::MAX {
+            self.reset();
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new()
+    }
+}
+
+/// Calls `attempt` until it succeeds, backing off between failures.
+pub fn retry<T>(what: &str, mut attempt: impl FnMut() -> Result<T, String>) -> T {
+    let mut backoff = Backoff::new();
+    loop {
+        match attempt() {
+            Ok(value) => return value,
+            Err(err) => {
+                let delay = backoff.next_delay();
+                warn!("{what}: {err}, retrying in {delay:?}");
+                std::thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// Every source and the name of the default sink.
+pub fn pulse_list_sources() -> Result<(Vec<PulseSourceInfo>, Option<String>), String> {
+    let (mut main_loop, ctx) = pulse_connect()?;
+    let sources = Arc::new(Mutex::new(Vec::new()));
+    let sources_clone = sources.clone();
     let op = ctx.introspect().get_source_info_list(move |info| {
         if let pulse::callbacks::ListResult::Item(item) = info {
-            let desc = item.description.clone().unwrap();
-            if desc.find(pattern).is_some() {
-                *name_clone.lock().unwrap() = item.name.clone().unwrap().to_string();
+            sources_clone.lock().unwrap().push(PulseSourceInfo {
+                name: item.name.as_deref().unwrap_or_default().to_string(),
+                description: item.description.as_deref().unwrap_or_default().to_string(),
+                monitor_of: item.monitor_of_sink_name.as_deref().map(str::to_string),
+            });
+        }
+    });
+    pulse_wait(&mut main_loop, &ctx, op);
+    let sources = sources.lock().unwrap().clone();
+    Ok((sources, pulse_default_sink(&mut main_loop, &ctx)))
+}
+
+fn pulse_default_sink(main_loop: &mut Mainloop, ctx: &Context) -> Option<String> {
+    let default_sink = Arc::new(Mutex::new(None));
+    let default_sink_clone = default_sink.clone();
+    let op = ctx.introspect().get_server_info(move |info| {
+        *default_sink_clone.lock().unwrap() = info.default_sink_name.as_deref().map(str::to_string);
+    });
+    pulse_wait(main_loop, ctx, op);
+    let default_sink = default_sink.lock().unwrap().clone();
+    default_sink
+}
+
+/// Latency of the sink `name`, or of the default sink, from its device: what is played after
+/// the streams' buffers.
+pub fn pulse_sink_latency(name: Option<&str>) -> Result<Duration, String> {
+    let (mut main_loop, ctx) = pulse_connect()?;
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => pulse_default_sink(&mut main_loop, &ctx).ok_or("PulseAudio has no default sink")?,
+    };
+    let latency = Arc::new(Mutex::new(None));
+    let latency_clone = latency.clone();
+    let op = ctx.introspect().get_sink_info_by_name(&name, move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            *latency_clone.lock().unwrap() = Some(Duration::from_micros(item.latency.0));
+        }
+    });
+    pulse_wait(&mut main_loop, &ctx, op);
+    let latency = *latency.lock().unwrap();
+    latency.ok_or_else(|| format!("No sink {name}"))
+}
+
+/// Every sink and the name of the default one.
+pub fn pulse_list_sinks() -> Result<(Vec<PulseSinkInfo>, Option<String>), String> {
+    let (mut main_loop, ctx) = pulse_connect()?;
+    let sinks = Arc::new(Mutex::new(Vec::new()));
+    let sinks_clone = sinks.clone();
+    let op = ctx.introspect().get_sink_info_list(move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            sinks_clone.lock().unwrap().push(PulseSinkInfo {
+                name: item.name.as_deref().unwrap_or_default().to_string(),
+                description: item.description.as_deref().unwrap_or_default().to_string(),
+            });
+        }
+    });
+    pulse_wait(&mut main_loop, &ctx, op);
+    let sinks = sinks.lock().unwrap().clone();
+    Ok((sinks, pulse_default_sink(&mut main_loop, &ctx)))
+}
+
+/// Sink named `pattern`, or whose description contains it, ignoring case.
+pub fn find_sink(sinks: &[PulseSinkInfo], pattern: &str) -> Result<String, String> {
+    let lowercase = pattern.to_lowercase();
+    sinks
+        .iter()
+        .find(|sink| sink.name == pattern)
+        .or_else(|| {
+            sinks
+                .iter()
+                .find(|sink| sink.description.to_lowercase().contains(&lowercase))
+        })
+        .map(|sink| sink.name.clone())
+        .ok_or_else(|| format!("No PulseAudio sink matches {pattern:?}, see list-sinks"))
+}
+
+pub fn pulse_get_sink_by_name(pattern: &str) -> Result<String, String> {
+    let (sinks, _) = pulse_list_sinks()?;
+    find_sink(&sinks, pattern)
+}
+
+/// Source named `pattern`, or whose description contains it ignoring case, like [`find_sink`].
+/// Without a pattern, the monitor of the default sink.
+pub fn find_source(
+    sources: &[PulseSourceInfo],
+    default_sink: Option<&str>,
+    pattern: Option<&str>,
+) -> Result<String, String> {
+    let found = match pattern {
+        Some(pattern) => sources
+            .iter()
+            .find(|source| source.name == pattern)
+            .or_else(|| {
+                let lowercase = pattern.to_lowercase();
+                sources
+                    .iter()
+                    .find(|source| source.description.to_lowercase().contains(&lowercase))
+            })
+            .ok_or_else(|| format!("No PulseAudio source matches {pattern:?}, see list-sources"))?,
+        None => {
+            let sink = default_sink.ok_or("PulseAudio has no default sink")?;
+            sources
+                .iter()
+                .find(|source| source.monitor_of.as_deref() == Some(sink))
+                .ok_or_else(|| format!("The default sink {sink} has no monitor source"))?
+        }
+    };
+    Ok(found.name.clone())
+}
+
+pub fn pulse_get_source_by_name(pattern: Option<&str>) -> Result<String, String> {
+    let (sources, default_sink) = pulse_list_sources()?;
+    find_source(&sources, default_sink.as_deref(), pattern)
+}
+
+/// Calls `on_change` from a background thread whenever the default sink changes or a sink or
+/// source is added or removed, and after reconnecting to a restarted server.
+pub fn pulse_watch_devices(on_change: impl Fn() + Send + Sync + 'static) {
+    use pulse::context::subscribe::{Facility, InterestMaskSet, Operation};
+
+    let on_change = Arc::new(on_change);
+    let mut backoff = Backoff::new();
+    std::thread::spawn(move || loop {
+        let (mut main_loop, mut ctx) = retry("Following devices", pulse_connect);
+        let connected = Instant::now();
+        let on_event = on_change.clone();
+        ctx.set_subscribe_callback(Some(Box::new(move |facility, operation, _| {
+            match (facility, operation) {
+                (Some(Facility::Server), _)
+                | (
+                    Some(Facility::Sink | Facility::Source),
+                    Some(Operation::New | Operation::Removed),
+                ) => {
+                    debug!("Pulse {facility:?} {operation:?}");
+                    on_event();
+                }
+                _ => {}
+            }
+        })));
+        ctx.subscribe(
+            InterestMaskSet::SERVER | InterestMaskSet::SINK | InterestMaskSet::SOURCE,
+            |_| {},
+        );
+        let err = loop {
+            if let Err(err) = pulse_iterate(&mut main_loop, &ctx) {
+                break err;
             }
+        };
+        backoff.reset_if_held(connected);
+        let delay = backoff.next_delay();
+        warn!("Lost the PulseAudio connection: {err}, reconnecting in {delay:?}");
+        std::thread::sleep(delay);
+        // Everything may have changed while it was gone
+        on_change();
+    });
+}
+
+/// Sink whose monitor is the source `pattern` resolves to, see [`find_source`].
+pub fn pulse_monitored_sink(pattern: Option<&str>) -> Result<String, String> {
+    let (sources, default_sink) = pulse_list_sources()?;
+    let name = find_source(&sources, default_sink.as_deref(), pattern)?;
+    sources
+        .into_iter()
+        .find(|source| source.name == name)
+        .and_then(|source| source.monitor_of)
+        .ok_or_else(|| format!("{name} is not the monitor of a sink"))
+}
+
+/// Volume of a sink as mixers show it, above 100% counts as 100%.
+fn pulse_to_volume(volume: pulse::volume::Volume, muted: bool) -> Volume {
+    let percent =
+        f64::from(volume.0) * f64::from(Volume::MAX) / f64::from(pulse::volume::Volume::NORMAL.0);
+    Volume {
+        percent: percent.round().min(Volume::MAX.into()) as u8,
+        muted,
+    }
+}
+
+fn volume_to_pulse(volume: Volume) -> pulse::volume::Volume {
+    pulse::volume::Volume(
+        pulse::volume::Volume::NORMAL.0 * u32::from(volume.percent) / u32::from(Volume::MAX),
+    )
+}
+
+/// Volume of the sink `name` and its number of channels.
+fn pulse_sink_volume(main_loop: &mut Mainloop, ctx: &Context, name: &str) -> Option<(Volume, u8)> {
+    let volume = Arc::new(Mutex::new(None));
+    let volume_clone = volume.clone();
+    let op = ctx.introspect().get_sink_info_by_name(name, move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            *volume_clone.lock().unwrap() = Some((
+                pulse_to_volume(item.volume.avg(), item.mute),
+                item.volume.len(),
+            ));
         }
     });
-    while let pulse::operation::State::Running = op.get_state() {
-        main_loop.iterate(false);
+    pulse_wait(main_loop, ctx, op);
+    let volume = *volume.lock().unwrap();
+    volume
+}
+
+/// Sets the volume of the sink followed by [`pulse_watch_sink_volume`], through its connection.
+pub struct SinkVolumeControl {
+    socket: UnixDatagram,
+}
+
+impl SinkVolumeControl {
+    pub fn set(&self, volume: Volume) -> Result<(), String> {
+        self.socket
+            .send(&Control::Volume(volume).to_bytes())
+            .map(|_| ())
+            .map_err(|err| format!("Fail to reach the volume watcher: {err}"))
+    }
+}
+
+/// Calls `on_change` from a background thread with the volume of the sink `sink` names, once
+/// at first and then whenever it changes, and sets it from the returned control. `sink` is
+/// asked again when sinks come and go or the default sink changes, errors just mean there is
+/// no volume to follow for now.
+pub fn pulse_watch_sink_volume(
+    sink: impl Fn() -> Result<String, String> + Send + 'static,
+    on_change: impl Fn(Volume) + Send + 'static,
+) -> Result<SinkVolumeControl, String> {
+    use pulse::{
+        context::subscribe::{Facility, InterestMaskSet, Operation},
+        mainloop::{api::Mainloop as _, events::io::FlagSet as IoEventFlagSet},
+        volume::ChannelVolumes,
+    };
+    use std::{cell::Cell, os::fd::AsRawFd, rc::Rc};
+
+    let (control, requests) =
+        UnixDatagram::pair().map_err(|err| format!("Fail to create the volume socket: {err}"))?;
+    requests
+        .set_nonblocking(true)
+        .map_err(|err| format!("Fail to create the volume socket: {err}"))?;
+    std::thread::spawn(move || {
+        let mut last = None;
+        let mut backoff = Backoff::new();
+        loop {
+            let (mut main_loop, mut ctx) = retry("Following the sink volume", pulse_connect);
+            let connected = Instant::now();
+            let (resolve, changed) = (Rc::new(Cell::new(true)), Rc::new(Cell::new(true)));
+            let (resolve_flag, changed_flag) = (resolve.clone(), changed.clone());
+            ctx.set_subscribe_callback(Some(Box::new(move |facility, operation, _| {
+                match (facility, operation) {
+                    (Some(Facility::Server), _)
+                    | (Some(Facility::Sink), Some(Operation::New | Operation::Removed)) => {
+                        resolve_flag.set(true);
+                        changed_flag.set(true);
+                    }
+                    (Some(Facility::Sink), _) => changed_flag.set(true),
+                    _ => {}
+                }
+            })));
+            ctx.subscribe(InterestMaskSet::SINK | InterestMaskSet::SERVER, |_| {});
+            let wanted = Rc::new(Cell::new(None));
+            let wanted_clone = wanted.clone();
+            let reader = requests
+                .try_clone()
+                .expect("Fail to clone the volume socket");
+            let _requests = main_loop.new_io_event(
+                requests.as_raw_fd(),
+                IoEventFlagSet::INPUT,
+                Box::new(move |_, _, _| {
+                    let mut packet = [0; CONTROL_MAX_LEN];
+                    while let Ok(len) = reader.recv(&mut packet) {
+                        if let Some(Control::Volume(volume)) = Control::parse(&packet[..len]) {
+                            wanted_clone.set(Some(volume));
+                        }
+                    }
+                }),
+            );
+            let mut followed: Option<(String, u8)> = None;
+            let err = loop {
+                if resolve.replace(false) {
+                    followed = match sink() {
+                        Ok(name) => pulse_sink_volume(&mut main_loop, &ctx, &name)
+                            .map(|(_, channels)| (name, channels)),
+                        Err(err) => {
+                            debug!("No sink volume to follow: {err}");
+                            None
+                        }
+                    };
+                }
+                if let Some(volume) = wanted.take() {
+                    match &followed {
+                        Some((name, channels)) => {
+                            let mut volumes = ChannelVolumes::default();
+                            volumes.set(*channels, volume_to_pulse(volume));
+                            let mut introspect = ctx.introspect();
+                            let op = introspect.set_sink_volume_by_name(name, &volumes, None);
+                            pulse_wait(&mut main_loop, &ctx, op);
+                            let op = introspect.set_sink_mute_by_name(name, volume.muted, None);
+                            pulse_wait(&mut main_loop, &ctx, op);
+                        }
+                        None => error!("Fail to set the sink volume: no sink to follow"),
+                    }
+                }
+                if changed.replace(false) {
+                    if let Some((name, _)) = &followed {
+                        match pulse_sink_volume(&mut main_loop, &ctx, name) {
+                            Some((volume, _)) if last != Some(volume) => {
+                                debug!("Volume of {name}: {volume}");
+                                last = Some(volume);
+                                on_change(volume);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                if let Err(err) = pulse_iterate(&mut main_loop, &ctx) {
+                    break err;
+                }
+            };
+            backoff.reset_if_held(connected);
+            let delay = backoff.next_delay();
+            warn!("Lost the PulseAudio connection: {err}, reconnecting in {delay:?}");
+            std::thread::sleep(delay);
+        }
+    });
+    Ok(SinkVolumeControl { socket: control })
+}
+
+type ExitHook = Box<dyn FnOnce() + Send>;
+
+static EXIT_HOOKS: Mutex<Vec<ExitHook>> = Mutex::new(Vec::new());
+
+/// Runs `hook` when the server is interrupted or terminated, hooks run in reverse order.
+pub fn at_exit(hook: impl FnOnce() + Send + 'static) {
+    EXIT_HOOKS.lock().unwrap().push(Box::new(hook));
+}
+
+/// Makes SIGINT and SIGTERM run the [`at_exit`] hooks before exiting.
+fn run_exit_hooks() {
+    let hooks = std::mem::take(&mut *EXIT_HOOKS.lock().unwrap());
+    for hook in hooks.into_iter().rev() {
+        hook();
+    }
+}
+
+pub fn handle_exit_signals() {
+    ctrlc::set_handler(|| {
+        run_exit_hooks();
+        std::process::exit(0);
+    })
+    .expect("Fail to set the exit handler");
+}
+
+/// Logs `err` and exits with a failure, for errors the user has to fix such as a wrong device.
+pub fn exit_with_error(err: impl Display) -> ! {
+    error!("{err}");
+    run_exit_hooks();
+    std::process::exit(1);
+}
+
+/// Module argument for a device description, properties with spaces need quoting.
+fn pulse_description(key: &str, description: &str) -> String {
+    format!(
+        "{key}='device.description=\"{}\"'",
+        description.replace(['"', '\''], "")
+    )
+}
+
+/// Module that loaded the sink `name`, `None` when there is no such sink.
+fn pulse_sink_owner(main_loop: &mut Mainloop, ctx: &Context, name: &str) -> Option<u32> {
+    let owner = Arc::new(Mutex::new(None));
+    let owner_clone = owner.clone();
+    let op = ctx.introspect().get_sink_info_by_name(name, move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            *owner_clone.lock().unwrap() = Some(item.owner_module);
+        }
+    });
+    pulse_wait(main_loop, ctx, op);
+    let owner = owner.lock().unwrap().flatten();
+    owner
+}
+
+/// Module that loaded the source `name`, `None` when there is no such source.
+fn pulse_source_owner(main_loop: &mut Mainloop, ctx: &Context, name: &str) -> Option<u32> {
+    let owner = Arc::new(Mutex::new(None));
+    let owner_clone = owner.clone();
+    let op = ctx.introspect().get_source_info_by_name(name, move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            *owner_clone.lock().unwrap() = Some(item.owner_module);
+        }
+    });
+    pulse_wait(main_loop, ctx, op);
+    let owner = owner.lock().unwrap().flatten();
+    owner
+}
+
+/// Modules to unload at exit, by index, name and argument.
+static MODULES: Mutex<Vec<(u32, String, String)>> = Mutex::new(Vec::new());
+
+/// Unloads `module` at exit, once however many times it is registered.
+fn pulse_unload_at_exit(module: u32, name: &str, argument: &str) {
+    let mut modules = MODULES.lock().unwrap();
+    let entry = (module, name.to_string(), argument.to_string());
+    if modules.contains(&entry) {
+        return;
+    }
+    if modules.is_empty() {
+        at_exit(pulse_unload_modules);
+    }
+    modules.push(entry);
+}
+
+fn pulse_unload_modules() {
+    let modules = std::mem::take(&mut *MODULES.lock().unwrap());
+    let (mut main_loop, ctx) = match pulse_connect() {
+        Ok(connection) => connection,
+        Err(err) => return error!("Fail to unload modules: {err}"),
+    };
+    for (module, name, argument) in modules.into_iter().rev() {
+        // After a restart of the server the index may belong to another module
+        let ours = Arc::new(Mutex::new(false));
+        let ours_clone = ours.clone();
+        let op = ctx.introspect().get_module_info(module, move |info| {
+            if let pulse::callbacks::ListResult::Item(item) = info {
+                *ours_clone.lock().unwrap() = item.name.as_deref() == Some(name.as_str())
+                    && item.argument.as_deref() == Some(argument.as_str());
+            }
+        });
+        pulse_wait(&mut main_loop, &ctx, op);
+        if !*ours.lock().unwrap() {
+            continue;
+        }
+        let op = ctx.introspect().unload_module(module, |_| {});
+        pulse_wait(&mut main_loop, &ctx, op);
+        info!("Unloaded module {module}");
+    }
+}
+
+/// Loads a module until the server exits.
+fn pulse_load_module(
+    main_loop: &mut Mainloop,
+    ctx: &Context,
+    name: &str,
+    argument: &str,
+) -> Result<u32, String> {
+    let module = Arc::new(Mutex::new(None));
+    let module_clone = module.clone();
+    let op = ctx.introspect().load_module(name, argument, move |index| {
+        *module_clone.lock().unwrap() = Some(index);
+    });
+    pulse_wait(main_loop, ctx, op);
+    let module = module
+        .lock()
+        .unwrap()
+        .filter(|&index| index != pulse::def::INVALID_INDEX)
+        .ok_or_else(|| format!("Fail to load {name} {argument}"))?;
+    info!("Loaded {name}, module {module}");
+    pulse_unload_at_exit(module, name, argument);
+    Ok(module)
+}
+
+/// Takes over a module already loaded by a previous run that did not exit cleanly, so it is
+/// unloaded at exit like the ones loaded by this run. Only when it is `name` with `argument`, as
+/// this run would have loaded it, anything else belongs to the user and is left alone.
+fn pulse_adopt_module(
+    main_loop: &mut Mainloop,
+    ctx: &Context,
+    module: u32,
+    name: &str,
+    argument: &str,
+) -> Result<(), String> {
+    let info = Arc::new(Mutex::new(None));
+    let info_clone = info.clone();
+    let op = ctx.introspect().get_module_info(module, move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            *info_clone.lock().unwrap() = Some((
+                item.name.as_deref().unwrap_or_default().to_string(),
+                item.argument.as_deref().unwrap_or_default().to_string(),
+            ));
+        }
+    });
+    pulse_wait(main_loop, ctx, op);
+    let loaded = info
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| format!("No module {module}"))?;
+    if loaded.0 != name || loaded.1 != argument {
+        info!("Module {module} was not loaded by the relay, keeping it at exit");
+        return Ok(());
+    }
+    pulse_unload_at_exit(module, name, argument);
+    Ok(())
+}
+
+/// Sink the relay loads for itself, apps routed to it only play on the phone. Returns the name
+/// of its monitor source. The sink is unloaded at exit, also when it was left over by a previous
+/// run and reused.
+pub fn pulse_load_null_sink(name: &str, description: &str) -> Result<String, String> {
+    let (mut main_loop, ctx) = pulse_connect()?;
+    let monitor = format!("{name}.monitor");
+    let argument = format!(
+        "sink_name={name} {}",
+        pulse_description("sink_properties", description)
+    );
+    if let Some(module) = pulse_sink_owner(&mut main_loop, &ctx, name) {
+        info!("Reusing sink {name}");
+        pulse_adopt_module(&mut main_loop, &ctx, module, "module-null-sink", &argument)?;
+        return Ok(monitor);
+    }
+    pulse_load_module(&mut main_loop, &ctx, "module-null-sink", &argument)?;
+    Ok(monitor)
+}
+
+/// Source named `name` for other apps to record from, fed by playing into the returned sink.
+/// Both are unloaded at exit, also when they were left over by a previous run and reused.
+pub fn pulse_load_virtual_source(name: &str, description: &str) -> Result<String, String> {
+    let sink = format!("{name}_input");
+    let (mut main_loop, ctx) = pulse_connect()?;
+    let argument = format!(
+        "sink_name={sink} {}",
+        pulse_description("sink_properties", &format!("{description} input"))
+    );
+    if let Some(module) = pulse_sink_owner(&mut main_loop, &ctx, &sink) {
+        info!("Reusing sink {sink}");
+        pulse_adopt_module(&mut main_loop, &ctx, module, "module-null-sink", &argument)?;
+    } else {
+        pulse_load_module(&mut main_loop, &ctx, "module-null-sink", &argument)?;
+    }
+    // A plain source rather than a monitor, conferencing apps hide monitors
+    let argument = format!(
+        "master={sink}.monitor source_name={name} {}",
+        pulse_description("source_properties", description)
+    );
+    if let Some(module) = pulse_source_owner(&mut main_loop, &ctx, name) {
+        info!("Reusing source {name}");
+        pulse_adopt_module(
+            &mut main_loop,
+            &ctx,
+            module,
+            "module-remap-source",
+            &argument,
+        )?;
+    } else {
+        pulse_load_module(&mut main_loop, &ctx, "module-remap-source", &argument)?;
+    }
+    Ok(sink)
+}
+
+/// Application whose streams are relayed, by name or by process id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppMatch {
+    Name(String),
+    Pid(u32),
+}
+
+impl AppMatch {
+    /// Names match `application.name` or the process binary, ignoring case.
+    pub fn matches(&self, name: Option<&str>, binary: Option<&str>, pid: Option<&str>) -> bool {
+        match self {
+            AppMatch::Name(pattern) => [name, binary]
+                .into_iter()
+                .flatten()
+                .any(|value| value.to_lowercase().contains(&pattern.to_lowercase())),
+            AppMatch::Pid(expected) => pid.and_then(|pid| pid.parse().ok()) == Some(*expected),
+        }
+    }
+}
+
+impl Display for AppMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppMatch::Name(name) => write!(f, "{name}"),
+            AppMatch::Pid(pid) => write!(f, "pid {pid}"),
+        }
+    }
+}
+
+/// Moves the streams of `app` to the sink named `sink`, now and as they appear, from a
+/// background thread. They are moved back to the sink they came from at exit.
+pub fn pulse_capture_app(app: AppMatch, sink: &str) {
+    use std::collections::HashMap;
+
+    let sink = sink.to_string();
+    // Sink input -> the sink it played on before
+    let moved = Arc::new(Mutex::new(HashMap::<u32, u32>::new()));
+    let restore = moved.clone();
+    at_exit(move || {
+        let (mut main_loop, ctx) = match pulse_connect() {
+            Ok(connection) => connection,
+            Err(err) => return error!("Fail to restore the captured streams: {err}"),
+        };
+        for (input, original) in restore.lock().unwrap().drain() {
+            let op = ctx
+                .introspect()
+                .move_sink_input_by_index(input, original, None);
+            pulse_wait(&mut main_loop, &ctx, op);
+        }
+        info!("Restored the captured streams");
+    });
+    std::thread::spawn(move || {
+        let mut backoff = Backoff::new();
+        loop {
+            let err = capture_app_streams(&app, &sink, &moved, &mut backoff);
+            // Indexes do not survive a restart of the server
+            moved.lock().unwrap().clear();
+            let delay = backoff.next_delay();
+            warn!("Capturing {app}: {err}, retrying in {delay:?}");
+            std::thread::sleep(delay);
+        }
+    });
+}
+
+/// Moves the streams of `app` to `sink` until the connection is lost, returns why.
+fn capture_app_streams(
+    app: &AppMatch,
+    sink: &str,
+    moved: &Arc<Mutex<std::collections::HashMap<u32, u32>>>,
+    backoff: &mut Backoff,
+) -> String {
+    use pulse::{
+        context::subscribe::{Facility, InterestMaskSet, Operation},
+        proplist::properties,
+    };
+    use std::{cell::Cell, rc::Rc};
+
+    let (mut main_loop, mut ctx) = match pulse_connect() {
+        Ok(connection) => connection,
+        Err(err) => return err,
+    };
+    let sink_index = Arc::new(Mutex::new(None));
+    let sink_index_clone = sink_index.clone();
+    let op = ctx.introspect().get_sink_info_by_name(sink, move |info| {
+        if let pulse::callbacks::ListResult::Item(item) = info {
+            *sink_index_clone.lock().unwrap() = Some(item.index);
+        }
+    });
+    pulse_wait(&mut main_loop, &ctx, op);
+    // The sink is loaded again by the capture after a restart
+    let Some(sink_index) = *sink_index.lock().unwrap() else {
+        return format!("no sink {sink} to move the streams to");
+    };
+    backoff.reset();
+
+    let scan_due = Rc::new(Cell::new(true));
+    let scan_due_clone = scan_due.clone();
+    let removed = moved.clone();
+    ctx.set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
+        match (facility, operation) {
+            (Some(Facility::SinkInput), Some(Operation::New)) => scan_due_clone.set(true),
+            (Some(Facility::SinkInput), Some(Operation::Removed)) => {
+                removed.lock().unwrap().remove(&index);
+            }
+            _ => {}
+        }
+    })));
+    ctx.subscribe(InterestMaskSet::SINK_INPUT, |_| {});
+
+    loop {
+        if scan_due.replace(false) {
+            let found = Arc::new(Mutex::new(Vec::new()));
+            let found_clone = found.clone();
+            let wanted = app.clone();
+            let op = ctx.introspect().get_sink_input_info_list(move |info| {
+                if let pulse::callbacks::ListResult::Item(item) = info {
+                    let prop = |key| item.proplist.get_str(key);
+                    let matches = wanted.matches(
+                        prop(properties::APPLICATION_NAME).as_deref(),
+                        prop(properties::APPLICATION_PROCESS_BINARY).as_deref(),
+                        prop(properties::APPLICATION_PROCESS_ID).as_deref(),
+                    );
+                    if matches && item.sink != sink_index {
+                        found_clone.lock().unwrap().push((item.index, item.sink));
+                    }
+                }
+            });
+            pulse_wait(&mut main_loop, &ctx, op);
+            for (input, original) in found.lock().unwrap().drain(..) {
+                info!("Capturing {app} stream {input}");
+                moved.lock().unwrap().entry(input).or_insert(original);
+                let op = ctx
+                    .introspect()
+                    .move_sink_input_by_index(input, sink_index, None);
+                pulse_wait(&mut main_loop, &ctx, op);
+            }
+        }
+        if let Err(err) = pulse_iterate(&mut main_loop, &ctx) {
+            return format!("lost the PulseAudio connection: {err}");
+        }
     }
-    let owned_name = name.lock().unwrap().clone();
-    owned_name
 }
 
 pub enum PlayerControl {
@@ -111,6 +898,18 @@ pub fn udp_server_loop_data<const T: usize>(
     addr: &str,
     port: u16,
     func: impl Fn(Cow<str>, SocketAddr),
+) {
+    udp_server_loop_bytes::<T>(addr, port, |data, client| {
+        let message = String::from_utf8_lossy(data);
+        debug!("Received: {} from {}", message, client);
+        func(message, client);
+    })
+}
+
+pub fn udp_server_loop_bytes<const T: usize>(
+    addr: &str,
+    port: u16,
+    func: impl Fn(&[u8], SocketAddr),
 ) {
     let server_addr = format!("{}:{}", addr, port);
     let socket = UdpSocket::bind(&server_addr)
@@ -120,8 +919,100 @@ pub fn udp_server_loop_data<const T: usize>(
         let (nbytes, client) = socket
             .recv_from(&mut buffer)
             .expect("Failed to receive data");
-        let message = String::from_utf8_lossy(&buffer[..nbytes]);
-        debug!("Received: {} from {}", message, client);
-        func(message, client);
+        func(&buffer[..nbytes], client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_source() {
+        let source = |name: &str, description: &str, monitor_of: Option<&str>| PulseSourceInfo {
+            name: name.to_string(),
+            description: description.to_string(),
+            monitor_of: monitor_of.map(str::to_string),
+        };
+        let sources = [
+            source("alsa_input.mic", "Built-in Microphone", None),
+            source("jabra.monitor", "Monitor of Jabra Evolve", Some("jabra")),
+            source("hdmi.monitor", "Monitor of HDMI", Some("hdmi")),
+        ];
+        let find = |default_sink, pattern| find_source(&sources, default_sink, pattern);
+        assert_eq!(find(None, Some("hdmi.monitor")).unwrap(), "hdmi.monitor");
+        assert_eq!(find(None, Some("Jabra")).unwrap(), "jabra.monitor");
+        assert_eq!(find(None, Some("monitor of hdmi")).unwrap(), "hdmi.monitor");
+        assert_eq!(find(Some("hdmi"), None).unwrap(), "hdmi.monitor");
+        assert!(find(None, Some("Sony"))
+            .unwrap_err()
+            .contains("list-sources"));
+        assert!(find(None, None).is_err());
+        assert!(find(Some("bluez"), None).is_err());
+    }
+
+    #[test]
+    fn test_find_sink() {
+        let sink = |name: &str, description: &str| PulseSinkInfo {
+            name: name.to_string(),
+            description: description.to_string(),
+        };
+        let sinks = [
+            sink("alsa_output.analog-stereo", "Built-in Audio Analog Stereo"),
+            sink(
+                "alsa_output.hdmi-stereo",
+                "Built-in Audio Digital Stereo (HDMI)",
+            ),
+        ];
+        assert_eq!(
+            find_sink(&sinks, "alsa_output.hdmi-stereo").unwrap(),
+            "alsa_output.hdmi-stereo"
+        );
+        assert_eq!(
+            find_sink(&sinks, "analog").unwrap(),
+            "alsa_output.analog-stereo"
+        );
+        assert_eq!(
+            find_sink(&sinks, "hdmi").unwrap(),
+            "alsa_output.hdmi-stereo"
+        );
+        assert!(find_sink(&sinks, "bluez")
+            .unwrap_err()
+            .contains("list-sinks"));
+    }
+
+    #[test]
+    fn test_pulse_volume() {
+        let half = Volume {
+            percent: 50,
+            muted: false,
+        };
+        assert_eq!(pulse_to_volume(volume_to_pulse(half), false), half);
+        let loud = pulse::volume::Volume(pulse::volume::Volume::NORMAL.0 * 3 / 2);
+        assert_eq!(pulse_to_volume(loud, true).to_string(), "100% (muted)");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..8).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1600, 3200, 5000, 5000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+        backoff.reset_if_held(Instant::now());
+        assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+        backoff.reset_if_held(Instant::now() - Duration::from_secs(6));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_app_match() {
+        let firefox = AppMatch::Name("firefox".to_string());
+        assert!(firefox.matches(Some("Firefox"), None, Some("42")));
+        assert!(firefox.matches(Some("Web Content"), Some("firefox-bin"), None));
+        assert!(!firefox.matches(Some("Spotify"), Some("spotify"), Some("42")));
+        assert!(AppMatch::Pid(42).matches(Some("Spotify"), None, Some("42")));
+        assert!(!AppMatch::Pid(42).matches(Some("Spotify"), None, Some("420")));
+        assert!(!AppMatch::Pid(42).matches(None, None, None));
     }
 }
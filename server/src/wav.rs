@@ -0,0 +1,193 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+}
+
+impl WavSpec {
+    pub fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample as usize / 8
+    }
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.bytes_per_sample() * self.channels as usize
+    }
+}
+
+/// Interleaved little endian integer PCM, as stored in the data chunk.
+pub struct Wav {
+    pub spec: WavSpec,
+    pub data: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+impl Wav {
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Wav> {
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> io::Result<Wav> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("not a RIFF/WAVE file"));
+        }
+        let mut spec = None;
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let id = &bytes[pos..pos + 4];
+            let size = read_u32(bytes, pos + 4) as usize;
+            let body = pos + 8;
+            // Streamed files often leave the data size at 0 or 0xFFFFFFFF
+            let end = (body + size).min(bytes.len());
+            match id {
+                b"fmt " => {
+                    if end - body < 16 {
+                        return Err(invalid("fmt chunk too short"));
+                    }
+                    let mut format = read_u16(bytes, body);
+                    if format == WAVE_FORMAT_EXTENSIBLE && end - body >= 26 {
+                        format = read_u16(bytes, body + 24);
+                    }
+                    if format != WAVE_FORMAT_PCM {
+                        return Err(invalid("only integer PCM is supported"));
+                    }
+                    let parsed = WavSpec {
+                        channels: read_u16(bytes, body + 2),
+                        sample_rate: read_u32(bytes, body + 4),
+                        bits_per_sample: read_u16(bytes, body + 14),
+                    };
+                    if parsed.channels == 0 || !matches!(parsed.bits_per_sample, 16 | 24 | 32) {
+                        return Err(invalid("unsupported channel count or bit depth"));
+                    }
+                    spec = Some(parsed);
+                }
+                b"data" => {
+                    let spec = spec.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                    let len = (end - body) / spec.bytes_per_frame() * spec.bytes_per_frame();
+                    return Ok(Wav {
+                        spec,
+                        data: bytes[body..body + len].to_vec(),
+                    });
+                }
+                _ => {}
+            }
+            pos = body + size + (size & 1);
+        }
+        Err(invalid("missing data chunk"))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write_header(&mut out, self.spec, self.data.len())?;
+        out.write_all(&self.data)?;
+        out.flush()
+    }
+
+    pub fn frames(&self) -> usize {
+        self.data.len() / self.spec.bytes_per_frame()
+    }
+
+    /// Samples of every channel scaled to 24 bits, interleaved.
+    pub fn samples_i24(&self) -> Vec<i32> {
+        let width = self.spec.bytes_per_sample();
+        self.data
+            .chunks_exact(width)
+            .map(|s| match width {
+                2 => (i16::from_le_bytes([s[0], s[1]]) as i32) << 8,
+                3 => (i32::from_le_bytes([0, s[0], s[1], s[2]])) >> 8,
+                _ => i32::from_le_bytes([s[0], s[1], s[2], s[3]]) >> 8,
+            })
+            .collect()
+    }
+
+    /// Builds a wav from 24 bit samples, truncating to `bits_per_sample`.
+    pub fn from_samples_i24(spec: WavSpec, samples: &[i32]) -> Wav {
+        let mut data = Vec::with_capacity(samples.len() * spec.bytes_per_sample());
+        for &sample in samples {
+            let bytes = (sample << 8).to_le_bytes();
+            data.extend_from_slice(&bytes[4 - spec.bytes_per_sample()..]);
+        }
+        Wav { spec, data }
+    }
+}
+
+/// Writes a canonical 44 byte PCM header for `data_len` bytes of audio.
+pub fn write_header(out: &mut impl Write, spec: WavSpec, data_len: usize) -> io::Result<()> {
+    let data_len = data_len as u32;
+    let block_align = spec.bytes_per_frame() as u16;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&WAVE_FORMAT_PCM.to_le_bytes())?;
+    out.write_all(&spec.channels.to_le_bytes())?;
+    out.write_all(&spec.sample_rate.to_le_bytes())?;
+    out.write_all(&(spec.sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&spec.bits_per_sample.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wav_roundtrip() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 24,
+        };
+        let samples = vec![0, 1, -1, (1 << 23) - 1, -(1 << 23), 1234];
+        let wav = Wav::from_samples_i24(spec, &samples);
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, spec, wav.data.len()).unwrap();
+        bytes.extend_from_slice(&wav.data);
+        let parsed = Wav::parse(&bytes).unwrap();
+        assert_eq!(parsed.spec, spec);
+        assert_eq!(parsed.frames(), 3);
+        assert_eq!(parsed.samples_i24(), samples);
+    }
+
+    #[test]
+    fn test_wav_16bit_scaling() {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+        };
+        let wav = Wav::from_samples_i24(spec, &[256, -256]);
+        assert_eq!(wav.data, [1, 0, 0xFF, 0xFF]);
+        assert_eq!(wav.samples_i24(), [256, -256]);
+    }
+
+    #[test]
+    fn test_wav_rejects_garbage() {
+        assert!(Wav::parse(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(Wav::parse(b"not a wav file").is_err());
+    }
+}